@@ -0,0 +1,409 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cache::hash_file;
+use crate::fs_utils::write_atomic;
+
+/// Default backup location, relative to the working directory.
+pub const BACKUP_DIR_NAME: &str = ".images-optimizer-backup";
+/// Marks a backup root, so scans never pick up the backed-up images as sources.
+pub const BACKUP_MARKER: &str = ".images-optimizer-backup-root";
+const JOURNAL_FILE_NAME: &str = "journal.ndjson";
+const FILES_DIR_NAME: &str = "files";
+
+/// One line of a run journal. Lines are appended as the run goes, so a crash leaves a usable journal.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum JournalLine {
+    /// `path` is about to be changed for the first time in this run. `backup` names its previous
+    /// content under `files/`; `None` when the file didn't exist.
+    Saved { path: PathBuf, backup: Option<String> },
+    /// The run left `path` with content of this hash.
+    Written { path: PathBuf, hash: String },
+    /// The run removed `path`.
+    Removed { path: PathBuf },
+}
+
+/// Backups and journal of the current run. Created on the first change, so runs that change
+/// nothing leave nothing behind.
+#[derive(Debug)]
+pub struct Backup {
+    root: PathBuf,
+    state: Mutex<Option<RunState>>,
+}
+
+#[derive(Debug)]
+struct RunState {
+    dir: PathBuf,
+    journal: fs::File,
+    saved: HashSet<PathBuf>,
+}
+
+impl Backup {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root, state: Mutex::new(None) }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Directory of this run, once it has backed anything up.
+    pub fn run_dir(&self) -> Option<PathBuf> {
+        self.state.lock().unwrap().as_ref().map(|s| s.dir.clone())
+    }
+
+    /// Saves the current content of `path`, unless this run already did.
+    pub fn before_change(&self, path: &Path) -> io::Result<()> {
+        let path = absolute(path);
+        let mut guard = self.state.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(self.start_run()?);
+        }
+        let state = guard.as_mut().expect("run started");
+        if state.saved.contains(&path) {
+            return Ok(());
+        }
+        let backup = match fs::read(&path) {
+            Ok(data) => {
+                let name = format!("{}-{}", state.saved.len(), path.file_name().unwrap_or_default().to_string_lossy());
+                write_atomic(&state.dir.join(FILES_DIR_NAME).join(&name), &data)?;
+                Some(name)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        append(&mut state.journal, &JournalLine::Saved { path: path.clone(), backup })?;
+        state.saved.insert(path);
+        Ok(())
+    }
+
+    pub fn after_write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let hash = blake3::hash(data).to_hex().to_string();
+        self.log(JournalLine::Written { path: absolute(path), hash })
+    }
+
+    pub fn after_remove(&self, path: &Path) -> io::Result<()> {
+        self.log(JournalLine::Removed { path: absolute(path) })
+    }
+
+    fn log(&self, line: JournalLine) -> io::Result<()> {
+        match self.state.lock().unwrap().as_mut() {
+            Some(state) => append(&mut state.journal, &line),
+            None => Ok(()),
+        }
+    }
+
+    fn start_run(&self) -> io::Result<RunState> {
+        fs::create_dir_all(&self.root)?;
+        let marker = self.root.join(BACKUP_MARKER);
+        if !marker.exists() {
+            fs::write(marker, b"")?;
+        }
+        let stamp = utc_stamp(SystemTime::now());
+        let mut dir = self.root.join(&stamp);
+        let mut n = 1;
+        while dir.exists() {
+            n += 1;
+            dir = self.root.join(format!("{}-{}", stamp, n));
+        }
+        fs::create_dir_all(dir.join(FILES_DIR_NAME))?;
+        let journal = OpenOptions::new().create(true).append(true).open(dir.join(JOURNAL_FILE_NAME))?;
+        Ok(RunState { dir, journal, saved: HashSet::new() })
+    }
+}
+
+/// True when `path` lies inside a backup root.
+pub fn is_backup_path(path: &Path) -> bool {
+    path.ancestors().skip(1).any(|dir| dir.join(BACKUP_MARKER).exists())
+}
+
+/// A run found in a backup root.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunInfo {
+    pub id: String,
+    pub dir: PathBuf,
+    /// Files the run changed, created or removed.
+    pub files: usize,
+}
+
+/// Runs under `root`, oldest first.
+pub fn list_runs(root: &Path) -> io::Result<Vec<RunInfo>> {
+    let mut runs = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let dir = entry?.path();
+        let journal = dir.join(JOURNAL_FILE_NAME);
+        if !journal.is_file() {
+            continue;
+        }
+        let files = read_journal(&journal)?.len();
+        let id = dir.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        runs.push(RunInfo { id, dir, files });
+    }
+    runs.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(runs)
+}
+
+/// What `undo` did.
+#[derive(Debug, Default, Serialize)]
+pub struct UndoSummary {
+    pub run: String,
+    /// Files put back to their content before the run.
+    pub restored: Vec<PathBuf>,
+    /// Files the run had created, now deleted.
+    pub deleted: Vec<PathBuf>,
+    /// Files changed again since the run, or present where the run left nothing of its own, left alone.
+    pub conflicts: Vec<PathBuf>,
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+/// Reverts the run `id` under `root` (the latest one when `None`). Files changed since that run
+/// are skipped unless `force` is set. The run's backup is deleted once everything was reverted.
+pub fn undo(root: &Path, id: Option<&str>, force: bool) -> io::Result<UndoSummary> {
+    let runs = list_runs(root)?;
+    let run = match id {
+        Some(id) => runs.iter().find(|r| r.id == id),
+        None => runs.last(),
+    };
+    let Some(run) = run else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, match id {
+            Some(id) => format!("no run '{}' in {}", id, root.display()),
+            None => format!("no runs in {}", root.display()),
+        }));
+    };
+
+    let mut summary = UndoSummary { run: run.id.clone(), ..Default::default() };
+    for (path, state) in read_journal(&run.dir.join(JOURNAL_FILE_NAME))? {
+        let current = hash_file(&path).ok();
+        let saved = state.backup.as_ref().map(|name| run.dir.join(FILES_DIR_NAME).join(name));
+        let unchanged = match &state.last {
+            Some(Some(hash)) => current.as_ref() == Some(hash),
+            Some(None) => current.is_none(),
+            // Interrupted before the change was recorded: the file must still be as it was.
+            None => current == saved.as_deref().and_then(|saved| hash_file(saved).ok()),
+        };
+        if unchanged && state.last.is_none() {
+            continue;
+        }
+        // Only a file this run wrote, and that is still as it wrote it, is deleted; even `force`
+        // doesn't delete files the run didn't leave there.
+        let deletable = unchanged && matches!(state.last, Some(Some(_)));
+        if (!unchanged && !force) || (saved.is_none() && current.is_some() && !deletable) {
+            summary.conflicts.push(path);
+            continue;
+        }
+        let result = match saved {
+            Some(saved) => fs::read(saved)
+                .and_then(|data| write_atomic(&path, &data))
+                .map(|_| summary.restored.push(path.clone())),
+            None if current.is_some() => fs::remove_file(&path).map(|_| summary.deleted.push(path.clone())),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            summary.errors.push((path, e.to_string()));
+        }
+    }
+    if summary.conflicts.is_empty() && summary.errors.is_empty() {
+        fs::remove_dir_all(&run.dir)?;
+    }
+    Ok(summary)
+}
+
+#[derive(Debug)]
+struct PathState {
+    /// `Some(name)`: restore from `files/name`. `None`: the run created the file.
+    backup: Option<String>,
+    /// What the run left: `Some(Some(hash))` written, `Some(None)` removed, `None` untouched.
+    last: Option<Option<String>>,
+}
+
+fn read_journal(path: &Path) -> io::Result<Vec<(PathBuf, PathState)>> {
+    let mut order = Vec::new();
+    let mut states: HashMap<PathBuf, PathState> = HashMap::new();
+    for line in BufReader::new(fs::File::open(path)?).lines() {
+        // A torn last line from a crash is skipped.
+        let Ok(line) = serde_json::from_str::<JournalLine>(&line?) else { continue };
+        match line {
+            JournalLine::Saved { path, backup } => {
+                order.push(path.clone());
+                states.insert(path, PathState { backup, last: None });
+            }
+            JournalLine::Written { path, hash } => {
+                if let Some(s) = states.get_mut(&path) { s.last = Some(Some(hash)); }
+            }
+            JournalLine::Removed { path } => {
+                if let Some(s) = states.get_mut(&path) { s.last = Some(None); }
+            }
+        }
+    }
+    Ok(order.into_iter().filter_map(|p| states.remove(&p).map(|s| (p, s))).collect())
+}
+
+fn append(journal: &mut fs::File, line: &JournalLine) -> io::Result<()> {
+    let mut text = serde_json::to_string(line).map_err(io::Error::other)?;
+    text.push('\n');
+    journal.write_all(text.as_bytes())?;
+    journal.sync_data()
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    match fs::canonicalize(parent) {
+        Ok(dir) => dir.join(path.file_name().unwrap_or_default()),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// `20261016-203700`, in UTC.
+fn utc_stamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("images-optimizer-backup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(backup: &Backup, path: &Path, data: &[u8]) {
+        backup.before_change(path).unwrap();
+        fs::write(path, data).unwrap();
+        backup.after_write(path, data).unwrap();
+    }
+
+    fn remove(backup: &Backup, path: &Path) {
+        backup.before_change(path).unwrap();
+        fs::remove_file(path).unwrap();
+        backup.after_remove(path).unwrap();
+    }
+
+    /// A `--replace --webp` run: `a.png` recompressed, `a.webp` created, `c.tiff` converted to `c.png`.
+    fn run(dir: &Path) -> Backup {
+        fs::write(dir.join("a.png"), b"old png").unwrap();
+        fs::write(dir.join("c.tiff"), b"tiff").unwrap();
+        let backup = Backup::new(dir.join("bk"));
+        write(&backup, &dir.join("a.png"), b"new png");
+        write(&backup, &dir.join("a.webp"), b"webp");
+        write(&backup, &dir.join("c.png"), b"converted");
+        remove(&backup, &dir.join("c.tiff"));
+        backup
+    }
+
+    #[test]
+    fn undo_reverts_a_run() {
+        let dir = temp_dir("revert");
+        let backup = run(&dir);
+        let id = backup.run_dir().unwrap().file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(list_runs(&dir.join("bk")).unwrap().iter().map(|r| (r.id.as_str(), r.files)).collect::<Vec<_>>(), [(id.as_str(), 4)]);
+
+        let summary = undo(&dir.join("bk"), None, false).unwrap();
+        assert_eq!(summary.run, id);
+        assert_eq!(summary.restored.len(), 2);
+        assert_eq!(summary.deleted.len(), 2);
+        assert!(summary.conflicts.is_empty() && summary.errors.is_empty());
+        assert_eq!(fs::read(dir.join("a.png")).unwrap(), b"old png");
+        assert_eq!(fs::read(dir.join("c.tiff")).unwrap(), b"tiff");
+        assert!(!dir.join("a.webp").exists() && !dir.join("c.png").exists());
+        // A fully reverted run's backup is gone.
+        assert!(list_runs(&dir.join("bk")).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_edited_after_the_run_are_conflicts_unless_forced() {
+        let dir = temp_dir("conflict");
+        run(&dir);
+        fs::write(dir.join("a.png"), b"edited").unwrap();
+        fs::write(dir.join("a.webp"), b"edited webp").unwrap();
+
+        let summary = undo(&dir.join("bk"), None, false).unwrap();
+        assert_eq!(summary.conflicts.len(), 2);
+        assert_eq!(fs::read(dir.join("a.png")).unwrap(), b"edited");
+        assert_eq!(fs::read(dir.join("c.tiff")).unwrap(), b"tiff");
+        assert_eq!(list_runs(&dir.join("bk")).unwrap().len(), 1);
+
+        let summary = undo(&dir.join("bk"), None, true).unwrap();
+        assert_eq!(fs::read(dir.join("a.png")).unwrap(), b"old png");
+        // The edited WebP isn't what the run wrote, so not even `force` deletes it.
+        assert_eq!(summary.conflicts.len(), 1);
+        assert_eq!(fs::read(dir.join("a.webp")).unwrap(), b"edited webp");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_runs_leave_unrecorded_files_alone() {
+        let dir = temp_dir("interrupted");
+        fs::write(dir.join("a.png"), b"old png").unwrap();
+        let backup = Backup::new(dir.join("bk"));
+        // Saved, then the run died before writing either file.
+        backup.before_change(&dir.join("a.png")).unwrap();
+        backup.before_change(&dir.join("b.webp")).unwrap();
+        let journal = backup.run_dir().unwrap().join(JOURNAL_FILE_NAME);
+        OpenOptions::new().append(true).open(&journal).unwrap().write_all(b"{\"op\":\"writ").unwrap();
+        // Someone else creates the file afterwards.
+        fs::write(dir.join("b.webp"), b"not ours").unwrap();
+
+        for force in [false, true] {
+            let summary = undo(&dir.join("bk"), None, force).unwrap();
+            assert_eq!(summary.conflicts, [absolute(&dir.join("b.webp"))]);
+            assert!(summary.restored.is_empty() && summary.deleted.is_empty() && summary.errors.is_empty());
+        }
+        assert_eq!(fs::read(dir.join("b.webp")).unwrap(), b"not ours");
+        assert_eq!(fs::read(dir.join("a.png")).unwrap(), b"old png");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn journal_keeps_the_last_state_per_path_and_skips_torn_lines() {
+        let dir = temp_dir("journal");
+        let journal = dir.join(JOURNAL_FILE_NAME);
+        fs::write(&journal, [
+            r#"{"op":"saved","path":"/x/a.png","backup":"0-a.png"}"#,
+            r#"{"op":"saved","path":"/x/b.png","backup":null}"#,
+            r#"{"op":"written","path":"/x/a.png","hash":"h1"}"#,
+            r#"{"op":"written","path":"/x/unsaved.png","hash":"h2"}"#,
+            r#"{"op":"removed","path":"/x/a.png"}"#,
+            r#"{"op":"writ"#,
+        ].join("\n")).unwrap();
+
+        let entries = read_journal(&journal).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, PathBuf::from("/x/a.png"));
+        assert_eq!(entries[0].1.backup.as_deref(), Some("0-a.png"));
+        assert_eq!(entries[0].1.last, Some(None));
+        assert_eq!(entries[1].0, PathBuf::from("/x/b.png"));
+        assert_eq!(entries[1].1.last, None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stamps_are_utc_calendar_times() {
+        assert_eq!(utc_stamp(UNIX_EPOCH), "19700101-000000");
+        assert_eq!(utc_stamp(UNIX_EPOCH + std::time::Duration::from_secs(1_792_183_077)), "20261016-203757");
+    }
+}
@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::fs_utils::write_atomic;
+
+pub const CACHE_FILE_NAME: &str = ".images-optimizer-cache.json";
+const CACHE_VERSION: u32 = 1;
+
+/// What a source image with a given content hash was turned into, and under which settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub settings: String,
+    pub output: String,
+}
+
+/// Persistent manifest of already-optimized content, keyed by content hash.
+///
+/// Paths are deliberately not part of the key, so renamed or moved images stay cached.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cache {
+    version: u32,
+    entries: HashMap<String, CacheEntry>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    outputs: HashMap<String, String>,
+}
+
+impl Cache {
+    /// Loads the cache at `path`. A missing, unreadable or outdated file yields an empty cache.
+    pub fn load(path: &Path) -> Self {
+        let mut cache = fs::read(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Cache>(&data).ok())
+            .filter(|c| c.version == CACHE_VERSION)
+            .unwrap_or_default();
+        cache.version = CACHE_VERSION;
+        cache.path = path.to_path_buf();
+        cache.outputs = cache.entries.values()
+            .map(|e| (e.output.clone(), e.settings.clone()))
+            .collect();
+        cache
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let data = serde_json::to_vec(self).map_err(io::Error::other)?;
+        write_atomic(&self.path, &data)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// True when `hash` is the result of a previous run with the same `settings`, or a file that
+    /// run left as it was.
+    pub fn is_optimized(&self, hash: &str, settings: &str) -> bool {
+        self.outputs.get(hash).is_some_and(|s| s == settings) || self.output_for(hash, settings) == Some(hash)
+    }
+
+    /// The output hash previously produced from the source `hash` with `settings`.
+    pub fn output_for(&self, hash: &str, settings: &str) -> Option<&str> {
+        self.entries.get(hash)
+            .filter(|e| e.settings == settings)
+            .map(|e| e.output.as_str())
+    }
+
+    pub fn insert(&mut self, input: String, settings: String, output: String) {
+        self.outputs.insert(output.clone(), settings.clone());
+        self.entries.insert(input, CacheEntry { settings, output });
+    }
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(fs::File::open(path)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Default cache location for a target directory: a hidden file next to it.
+pub fn default_cache_path(target: &Path) -> PathBuf {
+    let target = fs::canonicalize(target).unwrap_or_else(|_| target.to_path_buf());
+    match (target.parent(), target.file_name()) {
+        (Some(parent), Some(name)) => parent.join(format!(".{}{}", name.to_string_lossy(), CACHE_FILE_NAME)),
+        _ => target.join(CACHE_FILE_NAME),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::ImageKind;
+    use crate::optimizer::FileSettings;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("images-optimizer-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn entries_are_keyed_by_content_not_path() {
+        let dir = temp_dir("keying");
+        fs::write(dir.join("a.png"), b"same").unwrap();
+        fs::write(dir.join("b.png"), b"same").unwrap();
+        fs::write(dir.join("c.png"), b"other").unwrap();
+        let [a, b, c] = ["a.png", "b.png", "c.png"].map(|name| hash_file(&dir.join(name)).unwrap());
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+
+        let mut cache = Cache::load(&dir.join(CACHE_FILE_NAME));
+        cache.insert(a.clone(), "s".to_string(), c.clone());
+        // A moved or renamed copy hits the same entry; its output counts as optimized as well.
+        assert_eq!(cache.output_for(&b, "s"), Some(c.as_str()));
+        assert!(cache.is_optimized(&c, "s"));
+        assert!(!cache.is_optimized(&a, "s"));
+        // A source left as it was is its own output.
+        cache.insert(c.clone(), "s".to_string(), c.clone());
+        assert!(cache.is_optimized(&c, "s"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_settings_invalidate_entries() {
+        let base = FileSettings::default();
+        let settings = base.cache_settings(ImageKind::Jpeg);
+        let mut cache = Cache::default();
+        cache.insert("in".to_string(), settings.clone(), "out".to_string());
+        assert_eq!(cache.output_for("in", &settings), Some("out"));
+
+        let changed = [
+            FileSettings { jpg_quality: base.jpg_quality - 1, ..FileSettings::default() },
+            FileSettings { webp: true, ..FileSettings::default() },
+            FileSettings { max_width: Some(640), ..FileSettings::default() },
+            FileSettings { widths: vec![320], ..FileSettings::default() },
+        ];
+        for other in changed {
+            let key = other.cache_settings(ImageKind::Jpeg);
+            assert_ne!(key, settings);
+            assert_eq!(cache.output_for("in", &key), None);
+            assert!(!cache.is_optimized("out", &key));
+        }
+        // Settings of other formats don't touch the key.
+        let png = FileSettings { png_min: 10, ..FileSettings::default() };
+        assert_eq!(png.cache_settings(ImageKind::Jpeg), settings);
+    }
+
+    #[test]
+    fn missing_corrupt_and_outdated_files_load_empty() {
+        let dir = temp_dir("corrupt");
+        let path = dir.join(CACHE_FILE_NAME);
+        assert!(Cache::load(&path).is_empty());
+
+        fs::write(&path, b"{\"version\": 1, \"entries\": {").unwrap();
+        assert!(Cache::load(&path).is_empty());
+        fs::write(&path, b"{\"version\": 0, \"entries\": {\"in\": {\"settings\": \"s\", \"output\": \"out\"}}}").unwrap();
+        assert!(Cache::load(&path).is_empty());
+
+        // An empty cache loaded from a bad file still saves over it.
+        let mut cache = Cache::load(&path);
+        cache.insert("in".to_string(), "s".to_string(), "out".to_string());
+        cache.save().unwrap();
+        let reloaded = Cache::load(&path);
+        assert_eq!(reloaded.len(), 1);
+        assert!(reloaded.is_optimized("out", "s"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,34 +1,205 @@
-use clap::{Parser, ValueHint};
-
-#[derive(Parser, Debug)]
-#[command(
-    author, 
-    version, 
-    about = "High-performance parallel image optimizer.",
-    long_about = "A multi-threaded CLI tool designed to compress JPG and PNG images recursively.\n\nIt utilizes mozjpeg, pngquant, and oxipng to reduce file sizes while preserving visual quality."
-)]
-pub struct Args {
-    #[arg(required = false, value_delimiter = ',', num_args = 1.., value_hint = ValueHint::AnyPath, help = "List of files or directories to process.")]
-    pub paths: Vec<String>,
-
-    #[arg(long, default_value_t = 80, value_parser = clap::value_parser!(u8).range(1..=100), help_heading = "Quality Settings", help = "Target JPEG quality (0-100).")]
-    pub jpg_q: u8,
-
-    #[arg(long, default_value_t = 65, value_parser = clap::value_parser!(u8).range(1..=100), help_heading = "Quality Settings", help = "Minimum PNG quality allowed (0-100).")]
-    pub png_min: u8,
-
-    #[arg(long, default_value_t = 80, value_parser = clap::value_parser!(u8).range(1..=100), help_heading = "Quality Settings", help = "Maximum PNG quality allowed (0-100).")]
-    pub png_max: u8,
-
-    #[arg(long, help_heading = "Format Generation", help = "Generate WebP versions alongside originals.")]
-    pub webp: bool,
-
-    #[arg(long, help_heading = "Format Generation", help = "Generate AVIF versions alongside originals.")]
-    pub avif: bool,
-
-    #[arg(long, help = "Overwrite original files in place.")]
-    pub replace: bool,
-
-    #[arg(short = 'S', long, help = "Suppress all standard output.")]
-    pub silent: bool,
-}
\ No newline at end of file
+use clap::{Parser, Subcommand, ValueEnum, ValueHint};
+use images_optimizer::backup::BACKUP_DIR_NAME;
+use images_optimizer::config::ByteSize;
+use images_optimizer::formats::ConvertRule;
+use images_optimizer::fs_utils::NonImages;
+use images_optimizer::image_ops::{ColorMode, PngStrip, ResizeFilter};
+use images_optimizer::metadata::MetadataPolicy;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(
+    author, 
+    version, 
+    about = "High-performance parallel image optimizer.",
+    long_about = "A multi-threaded CLI tool designed to compress JPG and PNG images recursively. WebP, GIF, TIFF, BMP and QOI inputs are recompressed or converted.\n\nIt utilizes mozjpeg, libimagequant, and oxipng to reduce file sizes while preserving visual quality.",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(required = false, value_delimiter = ',', num_args = 1.., value_hint = ValueHint::AnyPath, help = "List of files or directories to process.")]
+    pub paths: Vec<String>,
+
+    // Quality flags have no clap default so that config files apply unless a flag is given.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100), help_heading = "Quality Settings", help = "Target JPEG quality (1-100). [default: 80]")]
+    pub jpg_q: Option<u8>,
+
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100), help_heading = "Quality Settings", help = "Minimum PNG quality allowed (1-100). [default: 65]")]
+    pub png_min: Option<u8>,
+
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100), help_heading = "Quality Settings", help = "Maximum PNG quality allowed (1-100). [default: 80]")]
+    pub png_max: Option<u8>,
+
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=10), help_heading = "Quality Settings", help = "PNG quantization speed, 1 (slowest, best quality) to 10. [default: 3]")]
+    pub png_speed: Option<u8>,
+
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=6), help_heading = "Quality Settings", help = "oxipng optimization level (0-6). [default: 4]")]
+    pub png_level: Option<u8>,
+
+    #[arg(long, help_heading = "Quality Settings", help = "Other PNG chunks oxipng strips: none, safe, all. Metadata follows --metadata. [default: all]")]
+    pub png_strip: Option<PngStrip>,
+
+    #[arg(long, help_heading = "Quality Settings", help = "Compress PNGs with zopfli (much slower, slightly smaller).")]
+    pub zopfli: bool,
+
+    #[arg(long, help_heading = "Quality Settings", help = "Sources with an ICC profile: srgb (convert pixels to sRGB, unless --metadata keeps the profile) or preserve (embed the profile in every output). [default: srgb]")]
+    pub color: Option<ColorMode>,
+
+    #[arg(long, value_delimiter = ',', help_heading = "Quality Settings", help = "Source metadata to keep in every output: strip-all, keep-all, keep-icc, keep-copyright, strip-gps. Combine with commas. [default: strip-all]")]
+    pub metadata: Option<Vec<MetadataPolicy>>,
+
+    #[arg(long, conflicts_with = "target_dssim", help_heading = "Quality Settings", help = "Instead of fixed JPEG/WebP quality, pick the lowest quality per image whose SSIM against the source is at least this (0-1, e.g. 0.98). AVIF keeps its fixed quality.")]
+    pub target_ssim: Option<f64>,
+
+    #[arg(long, help_heading = "Quality Settings", help = "Like --target-ssim, with a maximum DSSIM instead (e.g. 0.002). Lower is stricter.")]
+    pub target_dssim: Option<f64>,
+
+    #[arg(long, value_name = "SIZE", help_heading = "Quality Settings", help = "Keep every JPEG/WebP/AVIF output at or under this size (e.g. 150k, 1.5M), using the highest quality that fits.")]
+    pub max_bytes: Option<ByteSize>,
+
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100), help_heading = "Quality Settings", help = "Lowest quality --max-bytes and --target-* may go down to. [default: 10]")]
+    pub min_quality: Option<u8>,
+
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), help_heading = "Quality Settings", help = "With --max-bytes, downscale images down to this width when even --min-quality doesn't fit.")]
+    pub min_width: Option<u32>,
+
+    #[arg(long, value_name = "FORMAT=TARGET", value_delimiter = ',', help_heading = "Format Generation", help = "What an input format is optimized into: keep (recompress in the same format), jpeg, png or webp. E.g. tiff=jpeg,gif=keep. [default: tiff=png,bmp=png,qoi=png]")]
+    pub convert: Option<Vec<ConvertRule>>,
+
+    #[arg(long, help_heading = "Format Generation", help = "Rename images whose extension doesn't match their content (a PNG saved as .jpg becomes .png).")]
+    pub fix_extensions: bool,
+
+    #[arg(long, help_heading = "Format Generation", help = "Generate WebP versions alongside originals.")]
+    pub webp: bool,
+
+    #[arg(long, help_heading = "Format Generation", help = "Generate AVIF versions alongside originals.")]
+    pub avif: bool,
+
+    #[arg(long, help_heading = "Format Generation", help = "Stem of the generated files of same-stem sources (logo.png, logo.jpg) that would otherwise overwrite each other. {name} and {source_ext} are substituted. [default: {name}.{source_ext}]")]
+    pub collision_name: Option<String>,
+
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), help_heading = "Resizing", help = "Downscale images wider than this many pixels. Aspect ratio is kept; images are never upscaled.")]
+    pub max_width: Option<u32>,
+
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), help_heading = "Resizing", help = "Downscale images taller than this many pixels.")]
+    pub max_height: Option<u32>,
+
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), help_heading = "Resizing", help = "Downscale images with more than this many pixels in total (width x height).")]
+    pub max_pixels: Option<u64>,
+
+    #[arg(long, help_heading = "Resizing", help = "Resampling filter for downscaling: nearest, triangle, catmull-rom, gaussian, lanczos3. [default: lanczos3]")]
+    pub resize_filter: Option<ResizeFilter>,
+
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u32).range(1..), help_heading = "Responsive Variants", help = "Also generate downscaled variants at these widths (e.g. 320,640,1280,1920) in every enabled format.")]
+    pub widths: Option<Vec<u32>>,
+
+    #[arg(long, help_heading = "Responsive Variants", help = "Variant file name. {name}, {width} and {ext} are substituted. [default: {name}-{width}w.{ext}]")]
+    pub variant_template: Option<String>,
+
+    #[arg(long, value_hint = ValueHint::FilePath, help_heading = "Responsive Variants", help = "Write a JSON manifest mapping each image to its outputs and their pixel sizes, for building srcset attributes.")]
+    pub manifest: Option<PathBuf>,
+
+    #[arg(long, help = "Overwrite original files in place.")]
+    pub replace: bool,
+
+    #[arg(long, value_name = "DIR", conflicts_with = "replace", value_hint = ValueHint::DirPath, help = "Write optimized images straight into a mirrored tree under DIR instead of copying the input to <name>__optimized first.")]
+    pub out_dir: Option<PathBuf>,
+
+    #[arg(long, default_value_t = NonImages::Copy, requires = "out_dir", help = "What --out-dir does with files that aren't optimized: copy, link (hard link) or skip.")]
+    pub non_images: NonImages,
+
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath, help = "Back up every file to DIR before it is overwritten or removed, with a journal for 'undo' (e.g. --backup .images-optimizer-backup).")]
+    pub backup: Option<PathBuf>,
+
+    #[arg(short = 'i', long, help_heading = "Incremental Mode", help = "Skip images already optimized with the same settings (uses a content-hash cache).")]
+    pub incremental: bool,
+
+    #[arg(long, value_hint = ValueHint::FilePath, requires = "incremental", help_heading = "Incremental Mode", help = "Cache file location. Defaults to a hidden file next to the target directory.")]
+    pub cache_file: Option<PathBuf>,
+
+    #[arg(long, conflicts_with = "watch", help = "Encode in memory and report projected savings without writing or copying anything.")]
+    pub dry_run: bool,
+
+    #[arg(short = 'w', long, help = "After the initial pass, keep running and optimize images as they are added or changed. Requires a single directory.")]
+    pub watch: bool,
+
+    #[arg(long, help = "Ignore images-optimizer.toml config files.")]
+    pub no_config: bool,
+
+    #[arg(long, value_name = "GLOB", help_heading = "Filtering", help = "Only optimize files matching this glob, relative to each input directory (e.g. '**/public/**'). Repeatable.")]
+    pub include: Vec<String>,
+
+    #[arg(long, value_name = "GLOB", help_heading = "Filtering", help = "Skip files and directories matching this glob, relative to each input directory (e.g. '**/raw/**'). Repeatable.")]
+    pub exclude: Vec<String>,
+
+    #[arg(long, help_heading = "Filtering", help = "Don't read .gitignore and .imageoptimizerignore files.")]
+    pub no_ignore: bool,
+
+    #[arg(long, help_heading = "Filtering", help = "Also scan hidden files and directories (names starting with a dot).")]
+    pub hidden: bool,
+
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..), help_heading = "Filtering", help = "Only scan this many levels deep. 1 means files directly inside each input directory.")]
+    pub max_depth: Option<u64>,
+
+    #[arg(short = 'L', long, help_heading = "Filtering", help = "Follow symbolic links. Without it they are skipped.")]
+    pub follow_symlinks: bool,
+
+    #[arg(long, value_hint = ValueHint::FilePath, help_heading = "Output", help = "Write a JSON report with per-file results and totals to this file.")]
+    pub report: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Human, help_heading = "Output", help = "How progress is shown. 'ndjson' streams one JSON event per line to stdout instead of the progress bar and summary.")]
+    pub progress_format: ProgressFormat,
+
+    #[arg(long, overrides_with = "no_pause", help_heading = "Output", help = "Wait for a key press before exiting. By default this only happens when launched by double-click on Windows.")]
+    pub pause: bool,
+
+    #[arg(long, overrides_with = "pause", help_heading = "Output", help = "Never wait for a key press before exiting.")]
+    pub no_pause: bool,
+
+    #[arg(short = 'S', long, help = "Suppress all standard output.")]
+    pub silent: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Restore the files changed by a previous run from its --backup.
+    Undo(UndoArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct UndoArgs {
+    #[arg(help = "Run to restore, as shown by --list. Defaults to the latest one.")]
+    pub run: Option<String>,
+
+    #[arg(long, value_name = "DIR", default_value = BACKUP_DIR_NAME, value_hint = ValueHint::DirPath, help = "Backup directory the run was made with.")]
+    pub backup: PathBuf,
+
+    #[arg(long, help = "List the runs in the backup directory instead of restoring one.")]
+    pub list: bool,
+
+    #[arg(long, help = "Also restore files that were changed again after the run.")]
+    pub force: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressFormat {
+    Human,
+    Ndjson,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_takes_a_directory() {
+        for backup in [&["--backup", "/tmp/b"][..], &["--backup=/tmp/b"]] {
+            let args = Args::try_parse_from([&["images-optimizer", "--replace"], backup, &["/tmp/t1"]].concat()).unwrap();
+            assert_eq!(args.backup, Some(PathBuf::from("/tmp/b")));
+            assert_eq!(args.paths, ["/tmp/t1"]);
+        }
+        assert!(Args::try_parse_from(["images-optimizer", "--replace", "/tmp/t1", "--backup"]).is_err());
+    }
+}
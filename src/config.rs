@@ -0,0 +1,385 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::formats::{FormatPolicy, ImageKind};
+use crate::image_ops::{ColorMode, PngStrip, ResizeFilter};
+use crate::metadata::MetadataPolicy;
+use crate::optimizer::FileSettings;
+use crate::quality::QualityTarget;
+
+pub const CONFIG_FILE_NAME: &str = "images-optimizer.toml";
+/// Gitignore-style file of paths to leave alone, honoured in and outside git repositories.
+pub const IGNORE_FILE_NAME: &str = ".imageoptimizerignore";
+
+/// Contents of an `images-optimizer.toml`. Every field is optional; unset fields inherit
+/// from the parent directory's config. Keys mirror the CLI flags.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(rename = "jpg-q")]
+    pub jpg_quality: Option<u8>,
+    pub png_min: Option<u8>,
+    pub png_max: Option<u8>,
+    pub png_speed: Option<u8>,
+    pub png_level: Option<u8>,
+    pub png_strip: Option<PngStrip>,
+    pub zopfli: Option<bool>,
+    /// Per input format: `keep` or the format to convert to, e.g. `{ tiff = "jpeg", gif = "keep" }`.
+    /// Formats not listed keep their inherited policy.
+    pub convert: Option<BTreeMap<ImageKind, FormatPolicy>>,
+    pub fix_extensions: Option<bool>,
+    pub color: Option<ColorMode>,
+    /// Metadata policies, e.g. `["keep-icc", "keep-copyright"]`.
+    pub metadata: Option<Vec<MetadataPolicy>>,
+    pub target_ssim: Option<f64>,
+    pub target_dssim: Option<f64>,
+    /// Byte limit for JPEG/WebP/AVIF outputs: a number of bytes or a string like `"150k"`.
+    pub max_bytes: Option<ByteSize>,
+    pub min_quality: Option<u8>,
+    pub min_width: Option<u32>,
+    pub webp: Option<bool>,
+    pub avif: Option<bool>,
+    /// Suffix for safe-mode copies (`photo__optimized.jpg`, `assets__optimized/`).
+    pub output_suffix: Option<String>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_pixels: Option<u64>,
+    pub resize_filter: Option<ResizeFilter>,
+    /// Responsive variant widths, e.g. `[320, 640, 1280, 1920]`.
+    pub widths: Option<Vec<u32>>,
+    /// Variant file name, e.g. `{name}-{width}w.{ext}`.
+    pub variant_template: Option<String>,
+    /// Stem for same-stem sources whose outputs would clash, e.g. `{name}.{source_ext}`.
+    pub collision_name: Option<String>,
+    /// Glob patterns, relative to the directory holding the config file.
+    pub exclude: Vec<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        config.validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [("jpg-q", self.jpg_quality), ("png-min", self.png_min), ("png-max", self.png_max), ("min-quality", self.min_quality)] {
+            if let Some(v) = value && !(1..=100).contains(&v) {
+                return Err(format!("{} must be between 1 and 100, got {}", name, v));
+            }
+        }
+        if let (Some(min), Some(max)) = (self.png_min, self.png_max) && min > max {
+            return Err(format!("png-min ({}) must not be greater than png-max ({})", min, max));
+        }
+        if let Some(v) = self.png_speed && !(1..=10).contains(&v) {
+            return Err(format!("png-speed must be between 1 and 10, got {}", v));
+        }
+        if self.target_ssim.is_some() && self.target_dssim.is_some() {
+            return Err("target-ssim and target-dssim can't both be set".to_string());
+        }
+        if let Some(v) = self.target_ssim && !(v > 0.0 && v <= 1.0) {
+            return Err(format!("target-ssim must be between 0 and 1, got {}", v));
+        }
+        if let Some(v) = self.target_dssim && (v.is_nan() || v < 0.0) {
+            return Err(format!("target-dssim must not be negative, got {}", v));
+        }
+        if let Some(v) = self.png_level && v > 6 {
+            return Err(format!("png-level must be between 0 and 6, got {}", v));
+        }
+        for (name, value) in [
+            ("max-width", self.max_width.map(u64::from)),
+            ("max-height", self.max_height.map(u64::from)),
+            ("max-pixels", self.max_pixels),
+            ("max-bytes", self.max_bytes.map(|b| b.0)),
+            ("min-width", self.min_width.map(u64::from)),
+        ] {
+            if value == Some(0) {
+                return Err(format!("{} must be greater than 0", name));
+            }
+        }
+        if let Some(widths) = &self.widths && widths.contains(&0) {
+            return Err("widths must be greater than 0".to_string());
+        }
+        if let Some(template) = &self.variant_template && !template.contains("{width}") {
+            return Err(format!("variant-template must contain {{width}}, got '{}'", template));
+        }
+        if let Some(name) = &self.collision_name && !name.contains("{name}") {
+            return Err(format!("collision-name must contain {{name}}, got '{}'", name));
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, settings: &mut FileSettings) {
+        if let Some(v) = self.jpg_quality { settings.jpg_quality = v; }
+        if let Some(v) = self.png_min { settings.png_min = v; }
+        if let Some(v) = self.png_max { settings.png_max = v; }
+        if let Some(v) = self.png_speed { settings.png_speed = v; }
+        if let Some(v) = self.png_level { settings.png_level = v; }
+        if let Some(v) = self.png_strip { settings.png_strip = v; }
+        if let Some(v) = self.zopfli { settings.zopfli = v; }
+        if let Some(v) = &self.convert { settings.convert.extend(v); }
+        if let Some(v) = self.fix_extensions { settings.fix_extensions = v; }
+        if let Some(v) = self.color { settings.color = v; }
+        if let Some(v) = &self.metadata { settings.metadata = v.clone(); }
+        if let Some(v) = self.target_ssim { settings.quality_target = Some(QualityTarget::Ssim(v)); }
+        if let Some(v) = self.target_dssim { settings.quality_target = Some(QualityTarget::Dssim(v)); }
+        if let Some(v) = self.max_bytes { settings.max_bytes = Some(v.0); }
+        if let Some(v) = self.min_quality { settings.min_quality = v; }
+        if let Some(v) = self.min_width { settings.min_width = Some(v); }
+        if let Some(v) = self.webp { settings.webp = v; }
+        if let Some(v) = self.avif { settings.avif = v; }
+        if let Some(v) = &self.output_suffix { settings.output_suffix = v.clone(); }
+        if let Some(v) = self.max_width { settings.max_width = Some(v); }
+        if let Some(v) = self.max_height { settings.max_height = Some(v); }
+        if let Some(v) = self.max_pixels { settings.max_pixels = Some(v); }
+        if let Some(v) = self.resize_filter { settings.resize_filter = v; }
+        if let Some(v) = &self.widths { settings.widths = v.clone(); }
+        if let Some(v) = &self.variant_template { settings.variant_template = v.clone(); }
+        if let Some(v) = &self.collision_name { settings.collision_name = v.clone(); }
+    }
+}
+
+/// A byte count written as `150000`, `150k`, `1.5M` or `2MiB`. Units are powers of 1024.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: f64 = number.parse().map_err(|_| format!("invalid size '{}'", s))?;
+        let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1u64,
+            "k" | "kb" | "kib" => 1 << 10,
+            "m" | "mb" | "mib" => 1 << 20,
+            "g" | "gb" | "gib" => 1 << 30,
+            _ => return Err(format!("invalid size unit in '{}'", s)),
+        };
+        Ok(ByteSize((number * multiplier as f64) as u64))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(n) => Ok(ByteSize(n)),
+            Raw::Text(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Exclude {
+    base: PathBuf,
+    set: GlobSet,
+}
+
+/// Effective configuration of one directory.
+#[derive(Debug)]
+pub struct DirConfig {
+    dir: PathBuf,
+    pub settings: Arc<FileSettings>,
+    excludes: Vec<Arc<Exclude>>,
+    /// Ignore files from the outermost directory down to this one.
+    ignores: Vec<Arc<Gitignore>>,
+    /// Inside a git repository, where `.gitignore` files count.
+    git: bool,
+}
+
+impl DirConfig {
+    /// True when `name` (a file or directory inside this directory) matches an exclude pattern.
+    pub fn is_excluded(&self, name: &std::ffi::OsStr) -> bool {
+        let path = self.dir.join(name);
+        self.excludes.iter().any(|ex| {
+            path.strip_prefix(&ex.base).is_ok_and(|rel| ex.set.is_match(rel))
+        })
+    }
+
+    /// True when `name` (a file or directory inside this directory) is ignored by a `.gitignore`
+    /// or `.imageoptimizerignore`. The deepest file with a matching pattern decides, so `!pattern`
+    /// lines re-include. Parent directories aren't checked; scans never enter ignored ones.
+    pub fn is_ignored(&self, name: &std::ffi::OsStr, is_dir: bool) -> bool {
+        let path = self.dir.join(name);
+        self.ignores.iter().rev()
+            .map(|ignore| ignore.matched(&path, is_dir))
+            .find(|m| !m.is_none())
+            .is_some_and(|m| m.is_ignore())
+    }
+}
+
+/// Resolves the effective settings for directories by layering, in order: the base settings,
+/// every config file from the filesystem root down to the directory, and the overrides.
+pub struct ConfigResolver {
+    base: FileSettings,
+    overrides: Config,
+    enabled: bool,
+    ignore_files: bool,
+    dirs: RefCell<HashMap<PathBuf, Arc<DirConfig>>>,
+}
+
+impl ConfigResolver {
+    /// `enabled` reads config files, `ignore_files` reads `.gitignore` and `.imageoptimizerignore`.
+    pub fn new(base: FileSettings, overrides: Config, enabled: bool, ignore_files: bool) -> Self {
+        Self { base, overrides, enabled, ignore_files, dirs: RefCell::new(HashMap::new()) }
+    }
+
+    pub fn resolve(&self, dir: &Path) -> io::Result<Arc<DirConfig>> {
+        if let Some(found) = self.dirs.borrow().get(dir) {
+            return Ok(found.clone());
+        }
+        let canonical = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        let resolved = if canonical != dir {
+            self.resolve(&canonical)?
+        } else {
+            self.resolve_canonical(&canonical)?
+        };
+        self.dirs.borrow_mut().insert(dir.to_path_buf(), resolved.clone());
+        Ok(resolved)
+    }
+
+    /// Effective config for the directory containing `file`.
+    pub fn resolve_file(&self, file: &Path) -> io::Result<Arc<DirConfig>> {
+        match file.parent() {
+            Some(p) if !p.as_os_str().is_empty() => self.resolve(p),
+            _ => self.resolve(Path::new(".")),
+        }
+    }
+
+    fn resolve_canonical(&self, dir: &Path) -> io::Result<Arc<DirConfig>> {
+        let parent = match dir.parent() {
+            Some(p) if self.enabled || self.ignore_files => Some(self.resolve(p)?),
+            _ => None,
+        };
+
+        let own = if self.enabled {
+            let path = dir.join(CONFIG_FILE_NAME);
+            if path.is_file() { Some(Config::load(&path)?) } else { None }
+        } else {
+            None
+        };
+
+        let git = self.ignore_files && (parent.as_ref().is_some_and(|p| p.git) || dir.join(".git").exists());
+        let mut ignores = parent.as_ref().map(|p| p.ignores.clone()).unwrap_or_default();
+        if self.ignore_files && let Some(ignore) = load_ignore_files(dir, git)? {
+            ignores.push(Arc::new(ignore));
+        }
+
+        if let (Some(parent), None) = (&parent, &own) {
+            return Ok(Arc::new(DirConfig {
+                dir: dir.to_path_buf(),
+                settings: parent.settings.clone(),
+                excludes: parent.excludes.clone(),
+                ignores,
+                git,
+            }));
+        }
+
+        // Overrides were already applied to the parent; re-applying them after this
+        // directory's config keeps them on top.
+        let mut settings = match &parent {
+            Some(p) => (*p.settings).clone(),
+            None => self.base.clone(),
+        };
+        let mut excludes = parent.map(|p| p.excludes.clone()).unwrap_or_default();
+
+        // Each file is valid on its own, but layers can still cross the PNG quality range.
+        // The parent was consistent, so this directory's file (or the command line) crossed it.
+        let source = match &own {
+            Some(_) => dir.join(CONFIG_FILE_NAME).display().to_string(),
+            None => "command line".to_string(),
+        };
+
+        if let Some(config) = own {
+            config.apply(&mut settings);
+            if !config.exclude.is_empty() {
+                let mut builder = GlobSetBuilder::new();
+                for pattern in &config.exclude {
+                    let glob = Glob::new(pattern)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", dir.join(CONFIG_FILE_NAME).display(), e)))?;
+                    builder.add(glob);
+                }
+                let set = builder.build().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                excludes.push(Arc::new(Exclude { base: dir.to_path_buf(), set }));
+            }
+        }
+        self.overrides.apply(&mut settings);
+        if settings.png_min > settings.png_max {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "{}: png-min ({}) must not be greater than png-max ({})", source, settings.png_min, settings.png_max,
+            )));
+        }
+
+        Ok(Arc::new(DirConfig { dir: dir.to_path_buf(), settings: Arc::new(settings), excludes, ignores, git }))
+    }
+}
+
+/// Patterns of the `.gitignore` (inside a git repository) and `.imageoptimizerignore` of `dir`.
+/// Like git, lines that don't parse are skipped rather than failing the run.
+fn load_ignore_files(dir: &Path, git: bool) -> io::Result<Option<Gitignore>> {
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found = false;
+    for name in [".gitignore", IGNORE_FILE_NAME] {
+        let path = dir.join(name);
+        if (name == IGNORE_FILE_NAME || git) && path.is_file() {
+            let _ = builder.add(path);
+            found = true;
+        }
+    }
+    if !found {
+        return Ok(None);
+    }
+    let ignore = builder.build().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", dir.display(), e)))?;
+    Ok(Some(ignore))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("images-optimizer-config-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn crossed_png_range_names_the_responsible_file() {
+        let dir = temp_dir("png-range");
+        let sub = dir.join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(dir.join(CONFIG_FILE_NAME), "png-max = 70\n").unwrap();
+        fs::write(sub.join(CONFIG_FILE_NAME), "png-min = 75\n").unwrap();
+
+        let resolver = ConfigResolver::new(FileSettings::default(), Config::default(), true, false);
+        assert_eq!(resolver.resolve(&dir).unwrap().settings.png_max, 70);
+        let err = resolver.resolve(&sub).unwrap_err().to_string();
+        assert!(err.contains(&sub.join(CONFIG_FILE_NAME).display().to_string()), "{}", err);
+        assert!(err.contains("png-min (75) must not be greater than png-max (70)"), "{}", err);
+
+        let overrides = Config { png_max: Some(50), ..Config::default() };
+        let err = ConfigResolver::new(FileSettings::default(), overrides, false, false).resolve(&dir).unwrap_err();
+        assert!(err.to_string().starts_with("command line: png-min (65)"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+use std::io;
+
+/// Why a single output could not be produced.
+#[derive(Debug)]
+pub enum OptimizeError {
+    Decode(String),
+    Encode(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for OptimizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptimizeError::Decode(e) => write!(f, "decode failed: {}", e),
+            OptimizeError::Encode(e) => write!(f, "encode failed: {}", e),
+            OptimizeError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OptimizeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OptimizeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<image::ImageError> for OptimizeError {
+    fn from(e: image::ImageError) -> Self {
+        match e {
+            image::ImageError::IoError(e) => OptimizeError::Io(e),
+            image::ImageError::Encoding(e) => OptimizeError::Encode(e.to_string()),
+            other => OptimizeError::Decode(other.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for OptimizeError {
+    fn from(e: io::Error) -> Self {
+        OptimizeError::Io(e)
+    }
+}
@@ -0,0 +1,140 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::io;
+use std::path::{Component, Path};
+
+use crate::optimizer::OptimizeOptions;
+
+/// The `--include`/`--exclude`, hidden file, depth and symlink rules of a directory scan. Paths are
+/// relative to the scanned input directory. Config excludes and ignore files are checked separately,
+/// per directory, through `DirConfig`.
+#[derive(Debug, Clone)]
+pub struct ScanFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    pub hidden: bool,
+    pub max_depth: Option<usize>,
+    pub follow_links: bool,
+}
+
+impl ScanFilter {
+    pub fn new(options: &OptimizeOptions) -> io::Result<Self> {
+        let include = if options.include.is_empty() { None } else { Some(glob_set(&options.include)?) };
+        Ok(Self {
+            include,
+            exclude: glob_set(&options.exclude)?,
+            hidden: options.hidden,
+            max_depth: options.max_depth,
+            follow_links: options.follow_links,
+        })
+    }
+
+    /// True when the scan shouldn't enter directory `rel`.
+    pub fn skips_dir(&self, rel: &Path) -> bool {
+        (!self.hidden && is_hidden(rel)) || self.exclude.is_match(rel)
+    }
+
+    /// True when file `rel` is left out.
+    pub fn skips_file(&self, rel: &Path) -> bool {
+        self.skips_dir(rel) || self.include.as_ref().is_some_and(|set| !set.is_match(rel))
+    }
+
+    /// True when `rel` lies deeper than `max_depth` (1 = directly inside the input directory).
+    pub fn is_too_deep(&self, rel: &Path) -> bool {
+        self.max_depth.is_some_and(|max| rel.components().count() > max)
+    }
+}
+
+/// Any component of `rel` starts with a dot.
+fn is_hidden(rel: &Path) -> bool {
+    rel.components().any(|c| matches!(c, Component::Normal(name) if name.to_string_lossy().starts_with('.')))
+}
+
+fn glob_set(patterns: &[String]) -> io::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        builder.add(glob);
+    }
+    builder.build().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Include patterns, exclude patterns, `hidden`, path, expected outcome.
+    type Case = (&'static [&'static str], &'static [&'static str], bool, &'static str, bool);
+
+    fn filter(include: &[&str], exclude: &[&str], hidden: bool, max_depth: Option<usize>) -> ScanFilter {
+        let options = OptimizeOptions {
+            include: include.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+            hidden,
+            max_depth,
+            ..Default::default()
+        };
+        ScanFilter::new(&options).unwrap()
+    }
+
+    #[test]
+    fn files_are_filtered() {
+        let cases: &[Case] = &[
+            (&[], &[], false, "a.png", false),
+            (&[], &[], false, "sub/a.png", false),
+            (&["*.png"], &[], false, "a.png", false),
+            (&["*.png"], &[], false, "sub/a.png", false),
+            (&["*.png"], &[], false, "a.jpg", true),
+            (&["icons/**"], &[], false, "icons/a/b.png", false),
+            (&["icons/**"], &[], false, "photos/b.png", true),
+            (&[], &["raw/**"], false, "raw/a.png", true),
+            (&[], &["raw/**"], false, "rawer/a.png", false),
+            (&[], &["**/*.9.png"], false, "ui/button.9.png", true),
+            // Excludes win over includes.
+            (&["*.png"], &["a.png"], false, "a.png", true),
+            (&[], &[], false, ".a.png", true),
+            (&[], &[], false, ".cache/a.png", true),
+            (&[], &[], true, ".cache/a.png", false),
+            (&[], &[], false, "a.b/c.png", false),
+        ];
+        for &(include, exclude, hidden, path, skipped) in cases {
+            let f = filter(include, exclude, hidden, None);
+            assert_eq!(f.skips_file(Path::new(path)), skipped, "{:?} {:?} hidden={} {}", include, exclude, hidden, path);
+        }
+    }
+
+    #[test]
+    fn directories_ignore_includes() {
+        let cases: &[Case] = &[
+            (&["*.png"], &[], false, "sub", false),
+            (&[], &["raw"], false, "raw", true),
+            (&[], &["raw/**"], false, "raw/deeper", true),
+            (&[], &[], false, ".git", true),
+            (&[], &[], true, ".git", false),
+        ];
+        for &(include, exclude, hidden, dir, skipped) in cases {
+            let f = filter(include, exclude, hidden, None);
+            assert_eq!(f.skips_dir(Path::new(dir)), skipped, "{:?} {:?} hidden={} {}", include, exclude, hidden, dir);
+        }
+    }
+
+    #[test]
+    fn depth_is_limited() {
+        // (max_depth, path, too deep)
+        let cases = [
+            (None, "a/b/c/d.png", false),
+            (Some(1), "a.png", false),
+            (Some(1), "a/b.png", true),
+            (Some(2), "a/b.png", false),
+            (Some(2), "a/b/c.png", true),
+        ];
+        for (max_depth, path, deep) in cases {
+            assert_eq!(filter(&[], &[], false, max_depth).is_too_deep(Path::new(path)), deep, "{:?} {}", max_depth, path);
+        }
+    }
+
+    #[test]
+    fn bad_patterns_are_rejected() {
+        let options = OptimizeOptions { exclude: vec!["a[".to_string()], ..Default::default() };
+        assert_eq!(ScanFilter::new(&options).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,269 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Bytes `ImageKind::sniff` looks at.
+pub const SNIFF_LEN: usize = 18;
+
+/// A supported input format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageKind {
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    WebP,
+    Gif,
+    #[serde(alias = "tif")]
+    Tiff,
+    Bmp,
+    Qoi,
+}
+
+impl ImageKind {
+    pub const ALL: [ImageKind; 7] = [ImageKind::Jpeg, ImageKind::Png, ImageKind::WebP, ImageKind::Gif, ImageKind::Tiff, ImageKind::Bmp, ImageKind::Qoi];
+    pub const NAMES: [&'static str; 7] = ["jpeg", "png", "webp", "gif", "tiff", "bmp", "qoi"];
+
+    /// Recognized file extensions, lowercase. The first one is used for converted outputs.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            ImageKind::Jpeg => &["jpg", "jpeg", "jpe"],
+            ImageKind::Png => &["png"],
+            ImageKind::WebP => &["webp"],
+            ImageKind::Gif => &["gif"],
+            ImageKind::Tiff => &["tif", "tiff"],
+            ImageKind::Bmp => &["bmp"],
+            ImageKind::Qoi => &["qoi"],
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        self.extensions()[0]
+    }
+
+    /// The format the extension of `path` claims. What the file really is comes from `detect`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_lowercase();
+        Self::ALL.into_iter().find(|kind| kind.extensions().contains(&ext.as_str()))
+    }
+
+    /// Identifies an image by its first bytes. `SNIFF_LEN` of them are enough.
+    pub fn sniff(header: &[u8]) -> Option<Self> {
+        match header {
+            [0xFF, 0xD8, 0xFF, ..] => Some(ImageKind::Jpeg),
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n', ..] => Some(ImageKind::Png),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(ImageKind::WebP),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(ImageKind::Gif),
+            [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => Some(ImageKind::Tiff),
+            [b'q', b'o', b'i', b'f', ..] => Some(ImageKind::Qoi),
+            // "BM" alone is too common a start for text; the DIB header size must be a known one too.
+            [b'B', b'M', _, _, _, _, _, _, _, _, _, _, _, _, a, b, c, d, ..]
+                if [12, 40, 52, 56, 64, 108, 124].contains(&u32::from_le_bytes([*a, *b, *c, *d])) => Some(ImageKind::Bmp),
+            _ => None,
+        }
+    }
+
+    /// Reads the start of the file at `path` to identify it. `None` for anything that isn't a
+    /// supported image, including unreadable files.
+    pub fn detect(path: &Path) -> Option<Self> {
+        let mut header = Vec::with_capacity(SNIFF_LEN);
+        File::open(path).ok()?.take(SNIFF_LEN as u64).read_to_end(&mut header).ok()?;
+        Self::sniff(&header)
+    }
+
+    /// Whether `data` is an animated WebP: one whose `VP8X` header has the animation flag, or that
+    /// holds `ANIM`/`ANMF` chunks. Decoding it keeps only the first frame.
+    pub fn is_animated_webp(data: &[u8]) -> bool {
+        webp_chunks(data).any(|(name, body)| match name {
+            b"VP8X" => body.first().is_some_and(|flags| flags & 0x02 != 0),
+            b"ANIM" | b"ANMF" => true,
+            _ => false,
+        })
+    }
+
+    /// Whether `data` is a WebP stored losslessly, in a `VP8L` chunk.
+    pub fn is_lossless_webp(data: &[u8]) -> bool {
+        webp_chunks(data).any(|(name, _)| name == b"VP8L")
+    }
+
+    /// Whether `data` is a TIFF with more than one page (IFD). Decoding it keeps only the first.
+    pub fn is_multi_page_tiff(data: &[u8]) -> bool {
+        if Self::sniff(data) != Some(ImageKind::Tiff) {
+            return false;
+        }
+        let little_endian = data.starts_with(b"II");
+        let read = |pos: usize, len: usize| -> Option<usize> {
+            let bytes = data.get(pos..pos + len)?;
+            let fold = |acc: usize, &b: &u8| (acc << 8) | b as usize;
+            Some(if little_endian { bytes.iter().rev().fold(0, fold) } else { bytes.iter().fold(0, fold) })
+        };
+        let next = read(4, 4).and_then(|ifd0| read(ifd0 + 2 + read(ifd0, 2)? * 12, 4));
+        next.is_some_and(|next| next != 0)
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ImageKind::Jpeg => "image/jpeg",
+            ImageKind::Png => "image/png",
+            ImageKind::WebP => "image/webp",
+            ImageKind::Gif => "image/gif",
+            ImageKind::Tiff => "image/tiff",
+            ImageKind::Bmp => "image/bmp",
+            ImageKind::Qoi => "image/qoi",
+        }
+    }
+}
+
+/// The top-level chunks of a WebP file, as name and (possibly truncated) body. Empty for anything else.
+fn webp_chunks(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = if ImageKind::sniff(data) == Some(ImageKind::WebP) { 12 } else { data.len() };
+    std::iter::from_fn(move || {
+        let header = data.get(pos..pos + 8)?;
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let body = &data[(pos + 8).min(data.len())..(pos + 8).saturating_add(len).min(data.len())];
+        pos = pos.saturating_add(8 + len + (len & 1));
+        Some((&header[..4], body))
+    })
+}
+
+impl FromStr for ImageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        Self::ALL.into_iter()
+            .find(|kind| kind.to_string() == s || kind.extensions().contains(&s.as_str()))
+            .ok_or_else(|| format!("unknown image format '{}', expected one of: {}", s, Self::NAMES.join(", ")))
+    }
+}
+
+impl fmt::Display for ImageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+/// What the optimized original of an input format is: the same format recompressed, or a
+/// conversion to one of the formats with a real optimizer behind it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FormatPolicy {
+    Keep,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    WebP,
+}
+
+impl FormatPolicy {
+    pub const NAMES: [&'static str; 4] = ["keep", "jpeg", "png", "webp"];
+
+    /// The format a `kind` input ends up in.
+    pub fn target(self, kind: ImageKind) -> ImageKind {
+        match self {
+            FormatPolicy::Keep => kind,
+            FormatPolicy::Jpeg => ImageKind::Jpeg,
+            FormatPolicy::Png => ImageKind::Png,
+            FormatPolicy::WebP => ImageKind::WebP,
+        }
+    }
+}
+
+impl FromStr for FormatPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "keep" => Ok(FormatPolicy::Keep),
+            "jpeg" | "jpg" => Ok(FormatPolicy::Jpeg),
+            "png" => Ok(FormatPolicy::Png),
+            "webp" => Ok(FormatPolicy::WebP),
+            _ => Err(format!("unknown format policy '{}', expected one of: {}", s, Self::NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for FormatPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+/// One `--convert` entry: `tiff=png`, `gif=keep`, …
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConvertRule {
+    pub from: ImageKind,
+    pub to: FormatPolicy,
+}
+
+impl FromStr for ConvertRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s.split_once('=').ok_or_else(|| format!("expected FORMAT=TARGET, got '{}'", s))?;
+        Ok(ConvertRule { from: from.trim().parse()?, to: to.trim().parse()? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("images-optimizer-formats-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn headers_are_sniffed() {
+        let mut bmp = b"BM\0\0\0\0\0\0\0\0\0\0\0\0".to_vec();
+        bmp.extend(40u32.to_le_bytes());
+        let cases: [(&[u8], Option<ImageKind>); 14] = [
+            (b"\xFF\xD8\xFF\xE0\0\x10JFIF", Some(ImageKind::Jpeg)),
+            (b"\x89PNG\r\n\x1A\n\0\0\0\x0DIHDR", Some(ImageKind::Png)),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", Some(ImageKind::WebP)),
+            (b"RIFF\x24\0\0\0WAVEfmt ", None),
+            (b"GIF87a", Some(ImageKind::Gif)),
+            (b"GIF89a", Some(ImageKind::Gif)),
+            (b"II*\0\x08\0\0\0", Some(ImageKind::Tiff)),
+            (b"MM\0*\0\0\0\x08", Some(ImageKind::Tiff)),
+            // Byte order and magic number have to agree.
+            (b"II\0*\0\0\0\x08", None),
+            (b"qoif\0\0\0\x10", Some(ImageKind::Qoi)),
+            (&bmp, Some(ImageKind::Bmp)),
+            // "BM" without a known DIB header size is just text.
+            (b"BMW is a car brand", None),
+            (b"\x89PNG", None),
+            (b"", None),
+        ];
+        for (header, kind) in cases {
+            assert_eq!(ImageKind::sniff(header), kind, "{:?}", String::from_utf8_lossy(header));
+        }
+    }
+
+    #[test]
+    fn detect_trusts_content_over_extension() {
+        let dir = temp_dir("detect");
+        let cases: [(&str, &[u8], Option<ImageKind>); 5] = [
+            ("photo.jpg", b"\x89PNG\r\n\x1A\n\0\0\0\x0DIHDR", Some(ImageKind::Png)),
+            ("scan.png", b"MM\0*\0\0\0\x08", Some(ImageKind::Tiff)),
+            ("notes.jpg", b"not an image at all", None),
+            ("empty.png", b"", None),
+            ("noext", b"GIF89a\x01\0\x01\0", Some(ImageKind::Gif)),
+        ];
+        for (name, data, kind) in cases {
+            let path = dir.join(name);
+            std::fs::write(&path, data).unwrap();
+            assert_eq!(ImageKind::detect(&path), kind, "{}", name);
+        }
+        assert_eq!(ImageKind::from_path(&dir.join("photo.jpg")), Some(ImageKind::Jpeg));
+        assert_eq!(ImageKind::detect(&dir.join("missing.png")), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,19 +1,146 @@
-use std::fs;
-use std::path::Path;
-
-pub fn copy_dir_recursive(src: &Path, dst: &Path) -> std::io::Result<()> {
-    if !dst.exists() {
-        fs::create_dir_all(dst)?;
-    }
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let ty = entry.file_type()?;
-        let dst_path = dst.join(entry.file_name());
-        if ty.is_dir() {
-            copy_dir_recursive(&entry.path(), &dst_path)?;
-        } else {
-            fs::copy(entry.path(), &dst_path)?;
-        }
-    }
-    Ok(())
-}
\ No newline at end of file
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Ends the name of every temporary file `write_atomic` creates, so scans can ignore leftovers.
+pub const TEMP_SUFFIX: &str = ".images-optimizer.tmp";
+
+/// Writes `data` to `path` without ever leaving a partial file there: the data goes to a temporary
+/// file in the same directory, is synced to disk and then renamed over `path`. An existing file's
+/// permissions are kept.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp = temp_path(path);
+    let result = (|| {
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(data)?;
+        if let Ok(existing) = fs::metadata(path) {
+            file.set_permissions(existing.permissions())?;
+        }
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+    sync_parent(path);
+    Ok(())
+}
+
+pub fn is_temp_file(name: &std::ffi::OsStr) -> bool {
+    name.to_string_lossy().ends_with(TEMP_SUFFIX)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let unique = COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{}.{}-{}{}", name, process::id(), unique, TEMP_SUFFIX))
+}
+
+/// Makes the rename itself durable. Directories can't be opened for syncing on Windows.
+fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+        if let Ok(dir) = fs::File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+pub fn copy_dir_recursive(src: &Path, dst: &Path) -> std::io::Result<()> {
+    copy_dir_recursive_filtered(src, dst, &|_, _| true)
+}
+
+/// Like `copy_dir_recursive`, but only copies files for which `should_copy(src, dst)` returns true.
+pub fn copy_dir_recursive_filtered<F>(src: &Path, dst: &Path, should_copy: &F) -> std::io::Result<()>
+where
+    F: Fn(&Path, &Path) -> bool,
+{
+    if !dst.exists() {
+        fs::create_dir_all(dst)?;
+    }
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let ty = entry.file_type()?;
+        let dst_path = dst.join(entry.file_name());
+        if ty.is_dir() {
+            copy_dir_recursive_filtered(&entry.path(), &dst_path, should_copy)?;
+        } else if should_copy(&entry.path(), &dst_path) {
+            fs::copy(entry.path(), &dst_path)?;
+        }
+    }
+    Ok(())
+}
+/// What an `--out-dir` run does with files it doesn't optimize.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NonImages {
+    /// Copy them into the output tree.
+    #[default]
+    Copy,
+    /// Hard-link them, falling back to a copy where linking isn't possible (e.g. across drives).
+    Link,
+    /// Leave them out.
+    Skip,
+}
+
+impl NonImages {
+    pub const NAMES: [&'static str; 3] = ["copy", "link", "skip"];
+}
+
+impl FromStr for NonImages {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "copy" => Ok(NonImages::Copy),
+            "link" => Ok(NonImages::Link),
+            "skip" => Ok(NonImages::Skip),
+            _ => Err(format!("unknown non-image mode '{}', expected one of: {}", s, Self::NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for NonImages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+/// Puts `src` at `dst` the way `mode` says, unless `dst` already holds the same size and is not
+/// older. The new file is swapped in with a rename, like `write_atomic`. Returns false when there
+/// was nothing to do.
+pub fn mirror_file(src: &Path, dst: &Path, mode: NonImages) -> io::Result<bool> {
+    if mode == NonImages::Skip {
+        return Ok(false);
+    }
+    let src_meta = fs::metadata(src)?;
+    if let Ok(dst_meta) = fs::metadata(dst)
+        && dst_meta.len() == src_meta.len()
+        && let (Ok(src_time), Ok(dst_time)) = (src_meta.modified(), dst_meta.modified())
+        && dst_time >= src_time {
+        return Ok(false);
+    }
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = temp_path(dst);
+    let result = (|| {
+        if mode == NonImages::Copy || fs::hard_link(src, &temp).is_err() {
+            fs::copy(src, &temp)?;
+        }
+        fs::rename(&temp, dst)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.map(|_| true)
+}
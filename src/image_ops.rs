@@ -1,124 +1,461 @@
-use std::fs;
-use std::path::Path;
-use std::process::Command;
-use std::io::Write;
-use image::{GenericImageView, DynamicImage};
-use rgb::FromSlice; 
-use crate::tools::{ToolPath, get_tool_ref};
-
-pub fn process_jpg(path: &Path, quality: u8) -> u64 {
-    let original_size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
-    let img = match image::open(path) {
-        Ok(i) => i.to_rgb8(),
-        Err(_) => return 0,
-    };
-    let width = img.width() as usize;
-    let height = img.height() as usize;
-    let pixels = img.as_raw();
-
-    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
-    comp.set_size(width, height);
-    comp.set_quality(quality as f32);
-    comp.set_progressive_mode();
-    comp.set_optimize_scans(true);
-    let mut comp = comp.start_compress(Vec::new()).unwrap();
-    
-    if comp.write_scanlines(pixels).is_ok() {
-        let compressed_data = match comp.finish() {
-            Ok(d) => d,
-            Err(_) => return 0,
-        };
-        let new_len = compressed_data.len() as u64;
-        if new_len > 0 && new_len < original_size {
-             if let Ok(mut f) = fs::File::create(path) {
-                 if f.write_all(&compressed_data).is_ok() {
-                     return original_size - new_len;
-                 }
-             }
-        }
-    }
-    0
-}
-
-pub fn process_png(path: &Path, pq: &ToolPath, oxi: &ToolPath, min: u8, max: u8) -> u64 {
-    let original_size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
-    
-    #[cfg(target_os = "windows")]
-    use std::os::windows::process::CommandExt;
-    #[cfg(target_os = "windows")]
-    const CREATE_NO_WINDOW: u32 = 0x08000000;
-
-    let mut cmd = Command::new(get_tool_ref(pq));
-    cmd.args([&format!("--quality={}-{}", min, max), "--speed=3", "--force", "--ext=.png", "--skip-if-larger"]).arg(path);
-    #[cfg(target_os = "windows")]
-    cmd.creation_flags(CREATE_NO_WINDOW);
-    let _ = cmd.output();
-
-    let mut cmd2 = Command::new(get_tool_ref(oxi));
-    cmd2.args(["-o", "4", "--strip", "all", "-t", "1"]).arg(path);
-    #[cfg(target_os = "windows")]
-    cmd2.creation_flags(CREATE_NO_WINDOW);
-    let _ = cmd2.output();
-
-    let new_size = fs::metadata(path).map(|m| m.len()).unwrap_or(original_size);
-    if original_size > new_size { original_size - new_size } else { 0 }
-}
-
-pub fn generate_webp(img: &DynamicImage, path: &Path, quality: f32, original_size: u64) -> u64 {
-    let webp_path = path.with_extension("webp");
-    let (width, height) = img.dimensions();
-    
-    let memory = match img {
-        DynamicImage::ImageRgba8(buf) => {
-             webp::Encoder::from_rgba(buf.as_raw(), width, height).encode(quality)
-        },
-        DynamicImage::ImageRgb8(buf) => {
-             webp::Encoder::from_rgb(buf.as_raw(), width, height).encode(quality)
-        },
-        _ => {
-            let buf = img.to_rgba8();
-            webp::Encoder::from_rgba(buf.as_raw(), width, height).encode(quality)
-        }
-    };
-
-    if fs::write(&webp_path, &*memory).is_ok() {
-        let webp_size = memory.len() as u64;
-        if original_size > webp_size {
-            return original_size - webp_size;
-        }
-    }
-    0
-}
-
-pub fn generate_avif(img: &DynamicImage, path: &Path, original_size: u64) -> u64 {
-    let avif_path = path.with_extension("avif");
-    let rgba = img.to_rgba8();
-    let width = rgba.width() as usize;
-    let height = rgba.height() as usize;
-    
-    let src_img = imgref::Img::new(
-        rgba.as_raw().as_slice().as_rgba(),
-        width,
-        height,
-    );
-
-    let enc = ravif::Encoder::new()
-        .with_quality(65.0) 
-        .with_speed(4)
-        .with_alpha_quality(70.0)
-        .encode_rgba(src_img);
-
-    match enc {
-        Ok(encoded_image) => {
-            let data = encoded_image.avif_file;
-            if fs::write(&avif_path, &data).is_ok() {
-                let avif_size = data.len() as u64;
-                if original_size > avif_size {
-                    return original_size - avif_size;
-                }
-            }
-        },
-        Err(e) => eprintln!("AVIF Error for {:?}: {}", path, e),
-    }
-    0
-}
\ No newline at end of file
+use std::borrow::Cow;
+use std::fmt;
+use std::num::NonZeroU8;
+use std::io::Cursor;
+use std::str::FromStr;
+use image::{AnimationDecoder, ColorType, Delay, Frame, GenericImageView, DynamicImage, ImageFormat, ImageOutputFormat, RgbImage, RgbaImage};
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::imageops::FilterType;
+use rgb::FromSlice;
+use serde::Deserialize;
+use crate::error::OptimizeError;
+use crate::metadata::Metadata;
+
+pub const WEBP_QUALITY: f32 = 75.0;
+pub const AVIF_QUALITY: f32 = 65.0;
+pub const AVIF_SPEED: u8 = 4;
+pub const AVIF_ALPHA_QUALITY: f32 = 70.0;
+/// NeuQuant sampling speed for GIF re-encodes, 1 (best) to 30.
+pub const GIF_SPEED: i32 = 10;
+
+/// Zopfli iterations when `zopfli` is enabled; oxipng's own default.
+const ZOPFLI_ITERATIONS: u8 = 15;
+
+/// Which PNG metadata chunks oxipng removes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PngStrip {
+    /// Keep every chunk.
+    None,
+    /// Remove chunks that don't affect how the image is displayed.
+    Safe,
+    /// Remove every non-critical chunk.
+    #[default]
+    All,
+}
+
+impl PngStrip {
+    pub const NAMES: [&'static str; 3] = ["none", "safe", "all"];
+
+    fn chunks(self) -> oxipng::StripChunks {
+        match self {
+            PngStrip::None => oxipng::StripChunks::None,
+            PngStrip::Safe => oxipng::StripChunks::Safe,
+            PngStrip::All => oxipng::StripChunks::All,
+        }
+    }
+}
+
+impl FromStr for PngStrip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(PngStrip::None),
+            "safe" => Ok(PngStrip::Safe),
+            "all" => Ok(PngStrip::All),
+            _ => Err(format!("unknown strip policy '{}', expected one of: {}", s, Self::NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for PngStrip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+/// Everything the PNG pipeline needs: quantization, then lossless oxipng recompression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PngOptions {
+    pub min: u8,
+    pub max: u8,
+    pub speed: u8,
+    /// oxipng optimization level, 0 to 6.
+    pub level: u8,
+    pub strip: PngStrip,
+    /// Use zopfli for much slower, slightly smaller compression.
+    pub zopfli: bool,
+}
+
+/// Resampling filter used when downscaling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    pub const NAMES: [&'static str; 5] = ["nearest", "triangle", "catmull-rom", "gaussian", "lanczos3"];
+
+    fn filter_type(self) -> FilterType {
+        match self {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl FromStr for ResizeFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nearest" => Ok(ResizeFilter::Nearest),
+            "triangle" => Ok(ResizeFilter::Triangle),
+            "catmull-rom" => Ok(ResizeFilter::CatmullRom),
+            "gaussian" => Ok(ResizeFilter::Gaussian),
+            "lanczos3" => Ok(ResizeFilter::Lanczos3),
+            _ => Err(format!("unknown filter '{}', expected one of: {}", s, Self::NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for ResizeFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let i = *self as usize;
+        f.write_str(Self::NAMES[i])
+    }
+}
+
+/// What happens to sources with an embedded ICC profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorMode {
+    /// Convert the pixels to sRGB; outputs need no profile. Sources whose profile the metadata
+    /// policy keeps are left alone.
+    #[default]
+    Srgb,
+    /// Keep the pixels and embed the source profile in every output.
+    Preserve,
+}
+
+impl ColorMode {
+    pub const NAMES: [&'static str; 2] = ["srgb", "preserve"];
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "srgb" => Ok(ColorMode::Srgb),
+            "preserve" => Ok(ColorMode::Preserve),
+            _ => Err(format!("unknown color mode '{}', expected one of: {}", s, Self::NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for ColorMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+/// Converts `img` from the color space of ICC profile `icc` to sRGB. `None` when the profile
+/// can't be used (unparsable, not RGB), already is sRGB, or the image is grayscale.
+pub fn convert_to_srgb(img: &DynamicImage, icc: &[u8]) -> Option<DynamicImage> {
+    if !matches!(img.color(), ColorType::Rgb8 | ColorType::Rgba8 | ColorType::Rgb16 | ColorType::Rgba16 | ColorType::Rgb32F | ColorType::Rgba32F) {
+        return None;
+    }
+    let input = qcms::Profile::new_from_slice(icc, false)?;
+    if input.is_sRGB() {
+        return None;
+    }
+    let output = qcms::Profile::new_sRGB();
+    if img.color().has_alpha() {
+        let transform = qcms::Transform::new(&input, &output, qcms::DataType::RGBA8, qcms::Intent::Perceptual)?;
+        let mut rgba = img.to_rgba8();
+        transform.apply(&mut rgba);
+        Some(DynamicImage::ImageRgba8(rgba))
+    } else {
+        let transform = qcms::Transform::new(&input, &output, qcms::DataType::RGB8, qcms::Intent::Perceptual)?;
+        let mut rgb = img.to_rgb8();
+        transform.apply(&mut rgb);
+        Some(DynamicImage::ImageRgb8(rgb))
+    }
+}
+
+/// Rotates and flips `img` so that it displays upright for EXIF orientation `orientation`.
+pub fn apply_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Size that fits `width`x`height` within all given limits, keeping the aspect ratio.
+/// `None` when the image already fits; images are never upscaled.
+pub fn fit_dimensions(width: u32, height: u32, max_width: Option<u32>, max_height: Option<u32>, max_pixels: Option<u64>) -> Option<(u32, u32)> {
+    let (w, h) = (width as f64, height as f64);
+    let mut scale = 1.0f64;
+    if let Some(mw) = max_width {
+        scale = scale.min(mw as f64 / w);
+    }
+    if let Some(mh) = max_height {
+        scale = scale.min(mh as f64 / h);
+    }
+    if let Some(mp) = max_pixels {
+        scale = scale.min((mp as f64 / (w * h)).sqrt());
+    }
+    if scale >= 1.0 {
+        return None;
+    }
+    // The epsilon keeps exact fits (e.g. 6000 * 2560/6000) from rounding down a pixel.
+    let fit = |v: f64| ((v * scale + 1e-6).floor() as u32).max(1);
+    Some((fit(w), fit(h)))
+}
+
+pub fn resize(img: &DynamicImage, width: u32, height: u32, filter: ResizeFilter) -> DynamicImage {
+    img.resize_exact(width, height, filter.filter_type())
+}
+
+/// Borrows the pixels when `img` already is 8-bit RGB; converts otherwise.
+fn as_rgb8(img: &DynamicImage) -> Cow<'_, RgbImage> {
+    match img {
+        DynamicImage::ImageRgb8(buf) => Cow::Borrowed(buf),
+        _ => Cow::Owned(img.to_rgb8()),
+    }
+}
+
+/// Borrows the pixels when `img` already is 8-bit RGBA; converts otherwise.
+fn as_rgba8(img: &DynamicImage) -> Cow<'_, RgbaImage> {
+    match img {
+        DynamicImage::ImageRgba8(buf) => Cow::Borrowed(buf),
+        _ => Cow::Owned(img.to_rgba8()),
+    }
+}
+
+/// Encodes already decoded pixels with mozjpeg.
+pub fn encode_jpg_image(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, OptimizeError> {
+    let img = as_rgb8(img);
+    let width = img.width() as usize;
+    let height = img.height() as usize;
+    let pixels = img.as_raw();
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(width, height);
+    comp.set_quality(quality as f32);
+    comp.set_progressive_mode();
+    comp.set_optimize_scans(true);
+    let mut comp = comp.start_compress(Vec::new()).map_err(|e| OptimizeError::Encode(e.to_string()))?;
+    comp.write_scanlines(pixels).map_err(|e| OptimizeError::Encode(e.to_string()))?;
+    comp.finish().map_err(|e| OptimizeError::Encode(e.to_string()))
+}
+
+/// Quantizes `img` to a palette, then runs oxipng on the result, without touching disk.
+/// `source` is the PNG the pixels were decoded from, if they were not modified since: it is used
+/// instead of a re-encode whenever quantization fails or doesn't make it smaller.
+pub fn encode_png_image(img: &DynamicImage, source: Option<&[u8]>, opts: &PngOptions) -> Result<Vec<u8>, OptimizeError> {
+    let quantized = quantize_png(img, opts.min, opts.max, opts.speed)?;
+    let data = match (quantized, source) {
+        (Some(q), Some(src)) if q.len() >= src.len() => Cow::Borrowed(src),
+        (Some(q), _) => Cow::Owned(q),
+        (None, Some(src)) => Cow::Borrowed(src),
+        (None, None) => {
+            let mut data = Vec::new();
+            img.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+                .map_err(|e| OptimizeError::Encode(e.to_string()))?;
+            Cow::Owned(data)
+        }
+    };
+    optimize_png(&data, opts)
+}
+
+/// Lossy palette quantization with libimagequant, encoded as an 8-bit indexed PNG.
+/// `Ok(None)` when the result can't reach `min` quality.
+pub fn quantize_png(img: &DynamicImage, min: u8, max: u8, speed: u8) -> Result<Option<Vec<u8>>, OptimizeError> {
+    let quant_err = |e: imagequant::Error| OptimizeError::Encode(format!("quantization failed: {}", e));
+    let rgba = as_rgba8(img);
+    let (width, height) = rgba.dimensions();
+
+    let mut attr = imagequant::new();
+    attr.set_speed(speed as i32).map_err(quant_err)?;
+    attr.set_quality(min, max).map_err(quant_err)?;
+    let mut liq_img = attr
+        .new_image_borrowed(rgba.as_raw().as_rgba(), width as usize, height as usize, 0.0)
+        .map_err(quant_err)?;
+    let mut res = match attr.quantize(&mut liq_img) {
+        Ok(res) => res,
+        Err(imagequant::Error::QualityTooLow) => return Ok(None),
+        Err(e) => return Err(quant_err(e)),
+    };
+    res.set_dithering_level(1.0).map_err(quant_err)?;
+    let (palette, pixels) = res.remapped(&mut liq_img).map_err(quant_err)?;
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(palette.iter().flat_map(|c| [c.r, c.g, c.b]).collect::<Vec<u8>>());
+    if palette.iter().any(|c| c.a < 255) {
+        encoder.set_trns(palette.iter().map(|c| c.a).collect::<Vec<u8>>());
+    }
+    let png_err = |e: png::EncodingError| OptimizeError::Encode(e.to_string());
+    let mut writer = encoder.write_header().map_err(png_err)?;
+    writer.write_image_data(&pixels).map_err(png_err)?;
+    writer.finish().map_err(png_err)?;
+    Ok(Some(out))
+}
+
+/// Lossless in-memory recompression with oxipng.
+pub fn optimize_png(data: &[u8], opts: &PngOptions) -> Result<Vec<u8>, OptimizeError> {
+    let mut oxi = oxipng::Options::from_preset(opts.level);
+    oxi.strip = opts.strip.chunks();
+    if opts.zopfli {
+        let iterations = NonZeroU8::new(ZOPFLI_ITERATIONS).expect("non-zero");
+        oxi.deflate = oxipng::Deflaters::Zopfli { iterations };
+    }
+    oxipng::optimize_from_memory(data, &oxi).map_err(|e| OptimizeError::Encode(format!("oxipng: {}", e)))
+}
+
+pub fn encode_webp(img: &DynamicImage, quality: f32) -> Result<Vec<u8>, OptimizeError> {
+    encode_webp_with(img, |encoder| encoder.encode(quality))
+}
+
+/// Encodes `img` as lossless WebP, for sources that were lossless to begin with.
+pub fn encode_webp_lossless(img: &DynamicImage) -> Result<Vec<u8>, OptimizeError> {
+    encode_webp_with(img, |encoder| encoder.encode_lossless())
+}
+
+fn encode_webp_with(img: &DynamicImage, encode: impl Fn(&webp::Encoder) -> webp::WebPMemory) -> Result<Vec<u8>, OptimizeError> {
+    let (width, height) = img.dimensions();
+
+    let memory = match img {
+        DynamicImage::ImageRgba8(buf) => {
+             encode(&webp::Encoder::from_rgba(buf.as_raw(), width, height))
+        },
+        DynamicImage::ImageRgb8(buf) => {
+             encode(&webp::Encoder::from_rgb(buf.as_raw(), width, height))
+        },
+        _ if img.color().has_alpha() => {
+            let buf = img.to_rgba8();
+            encode(&webp::Encoder::from_rgba(buf.as_raw(), width, height))
+        }
+        _ => {
+            let buf = img.to_rgb8();
+            encode(&webp::Encoder::from_rgb(buf.as_raw(), width, height))
+        }
+    };
+    Ok(memory.to_vec())
+}
+
+/// Every frame of a GIF, composited to full-canvas RGBA.
+pub fn decode_gif_frames(data: &[u8]) -> Result<Vec<Frame>, OptimizeError> {
+    Ok(GifDecoder::new(Cursor::new(data))?.into_frames().collect_frames()?)
+}
+
+/// How often a GIF animation repeats, from its `NETSCAPE2.0` (or `ANIMEXTS1.0`) application
+/// extension. `None` without one: the animation plays once.
+pub fn gif_repeat(data: &[u8]) -> Option<Repeat> {
+    let at = data.windows(14).position(|w| w == b"\x21\xFF\x0BNETSCAPE2.0" || w == b"\x21\xFF\x0BANIMEXTS1.0")? + 14;
+    match *data.get(at..at + 4)? {
+        [3, 1, lo, hi] => match u16::from_le_bytes([lo, hi]) {
+            0 => Some(Repeat::Infinite),
+            n => Some(Repeat::Finite(n)),
+        },
+        _ => None,
+    }
+}
+
+/// Encodes `img` as a GIF, or, with more than one of `frames`, the whole animation at the size of
+/// `img`, repeating as `repeat` says.
+pub fn encode_gif(img: &DynamicImage, frames: &[Frame], repeat: Option<Repeat>, filter: ResizeFilter) -> Result<Vec<u8>, OptimizeError> {
+    let mut out = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut out, GIF_SPEED);
+        let result = if frames.len() > 1 {
+            let (width, height) = img.dimensions();
+            if let Some(repeat) = repeat {
+                encoder.set_repeat(repeat).map_err(|e| OptimizeError::Encode(e.to_string()))?;
+            }
+            encoder.encode_frames(frames.iter().map(|frame| {
+                if frame.buffer().dimensions() == (width, height) {
+                    return frame.clone();
+                }
+                let scaled = resize(&DynamicImage::ImageRgba8(frame.buffer().clone()), width, height, filter);
+                Frame::from_parts(scaled.to_rgba8(), 0, 0, frame.delay())
+            }))
+        } else {
+            encoder.encode_frame(Frame::from_parts(as_rgba8(img).into_owned(), 0, 0, Delay::from_numer_denom_ms(0, 1)))
+        };
+        result.map_err(|e| OptimizeError::Encode(e.to_string()))?;
+    }
+    Ok(out)
+}
+
+/// Plain re-encode through the `image` crate, for formats without a dedicated optimizer.
+pub fn encode_as(img: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, OptimizeError> {
+    let mut out = Cursor::new(Vec::new());
+    img.write_to(&mut out, format).map_err(|e| OptimizeError::Encode(e.to_string()))?;
+    Ok(out.into_inner())
+}
+
+pub fn encode_avif(img: &DynamicImage, quality: f32, metadata: &Metadata) -> Result<Vec<u8>, OptimizeError> {
+    let width = img.width() as usize;
+    let height = img.height() as usize;
+
+    let encoder = ravif::Encoder::new()
+        .with_quality(quality)
+        .with_speed(AVIF_SPEED)
+        .with_alpha_quality(AVIF_ALPHA_QUALITY);
+
+    // Opaque images take ravif's RGB path: no RGBA copy and no alpha plane to encode.
+    let encoded_image = if img.color().has_alpha() {
+        let rgba = as_rgba8(img);
+        encoder.encode_rgba(imgref::Img::new(rgba.as_raw().as_rgba(), width, height))
+    } else {
+        let rgb = as_rgb8(img);
+        encoder.encode_rgb(imgref::Img::new(rgb.as_raw().as_rgb(), width, height))
+    };
+    metadata.apply_avif(encoded_image.map_err(|e| OptimizeError::Encode(e.to_string()))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn animation(repeat: Option<Repeat>) -> Vec<u8> {
+        let frames = (0..3u8).map(|i| Frame::from_parts(RgbaImage::from_pixel(8, 8, image::Rgba([i * 80, 0, 0, 255])), 0, 0, Delay::from_numer_denom_ms(100, 1)));
+        let mut out = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut out);
+            if let Some(repeat) = repeat {
+                encoder.set_repeat(repeat).unwrap();
+            }
+            encoder.encode_frames(frames).unwrap();
+        }
+        out
+    }
+
+    fn reencode(data: &[u8]) -> Vec<u8> {
+        let frames = decode_gif_frames(data).unwrap();
+        let img = DynamicImage::ImageRgba8(frames[0].buffer().clone());
+        encode_gif(&img, &frames, gif_repeat(data), ResizeFilter::default()).unwrap()
+    }
+
+    #[test]
+    fn gif_loop_count_survives_reencoding() {
+        let out = reencode(&animation(Some(Repeat::Finite(2))));
+        assert!(matches!(gif_repeat(&out), Some(Repeat::Finite(2))));
+        assert_eq!(decode_gif_frames(&out).unwrap().len(), 3);
+
+        assert!(matches!(gif_repeat(&reencode(&animation(Some(Repeat::Infinite)))), Some(Repeat::Infinite)));
+        // Without the extension, the animation plays once, before and after.
+        assert!(gif_repeat(&reencode(&animation(None))).is_none());
+    }
+}
@@ -0,0 +1,21 @@
+pub mod backup;
+pub mod cache;
+pub mod config;
+pub mod error;
+pub mod filters;
+pub mod formats;
+pub mod fs_utils;
+pub mod image_ops;
+pub mod metadata;
+pub mod optimizer;
+pub mod quality;
+pub mod report;
+pub mod results;
+pub mod watch;
+
+pub use config::Config;
+pub use error::OptimizeError;
+pub use formats::ImageKind;
+pub use optimizer::{Event, FileSettings, OptimizeOptions, Optimizer, Plan, PlanEntry};
+pub use report::{Manifest, Report};
+pub use results::{Dimensions, FileResult, NameCollision, OutputKind, OutputResult, Resized, RunSummary, Tuned};
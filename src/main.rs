@@ -1,196 +1,154 @@
 mod cli;
-mod tools;
-mod fs_utils;
-mod image_ops;
 
 use clap::{Parser, CommandFactory};
 use console::{style, Term};
 use humansize::{format_size, DECIMAL};
 use indicatif::{ProgressBar, ProgressStyle};
-use rayon::prelude::*;
-use std::ffi::OsStr;
-use std::fs;
-use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{Duration, Instant}; 
-use walkdir::WalkDir;
-
-use cli::Args;
-use tools::get_png_tools;
-use fs_utils::copy_dir_recursive;
-use image_ops::{process_jpg, process_png, generate_webp, generate_avif};
-
-fn main() {
-    let args = Args::parse();
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use cli::{Args, Command, ProgressFormat, UndoArgs};
+use images_optimizer::backup;
+use images_optimizer::image_ops::AVIF_QUALITY;
+use images_optimizer::report::event_line;
+use images_optimizer::{Config, Event, ImageKind, Manifest, NameCollision, OptimizeOptions, Optimizer, OutputKind, Report, Tuned};
+
+fn main() -> ExitCode {
+    let mut args = Args::parse();
+    if let Some(Command::Undo(undo)) = &args.command {
+        return run_undo(undo);
+    }
+    let ndjson = args.progress_format == ProgressFormat::Ndjson;
+    // Stdout belongs to the event stream; errors still go to stderr.
+    if ndjson {
+        args.silent = true;
+    }
 
     if args.paths.is_empty() {
         let mut cmd = Args::command();
         cmd.print_help().unwrap();
-        return;
+        return ExitCode::SUCCESS;
+    }
+
+    if args.watch && !(args.paths.len() == 1 && Path::new(&args.paths[0]).is_dir()) {
+        eprintln!("{}", style("--watch requires exactly one directory.").red());
+        return ExitCode::FAILURE;
     }
 
     let total_start_time = Instant::now();
 
-    if args.avif && !args.silent {
-        println!("{}", style("[!] WARNING: AVIF encoding is active.").red().bold());
-        println!("{}", style("    This process is extremely CPU intensive and may take significantly longer.").yellow());
-        println!("{}", style("    Ensure your system has adequate cooling and power.").yellow());
-        println!("{}", style("------------------------------------------------").dim());
+    let overrides = Config {
+        jpg_quality: args.jpg_q,
+        png_min: args.png_min,
+        png_max: args.png_max,
+        png_speed: args.png_speed,
+        png_level: args.png_level,
+        png_strip: args.png_strip,
+        zopfli: args.zopfli.then_some(true),
+        color: args.color,
+        metadata: args.metadata.clone(),
+        convert: args.convert.as_ref().map(|rules| rules.iter().map(|r| (r.from, r.to)).collect()),
+        fix_extensions: args.fix_extensions.then_some(true),
+        target_ssim: args.target_ssim,
+        target_dssim: args.target_dssim,
+        max_bytes: args.max_bytes,
+        min_quality: args.min_quality,
+        min_width: args.min_width,
+        webp: args.webp.then_some(true),
+        avif: args.avif.then_some(true),
+        max_width: args.max_width,
+        max_height: args.max_height,
+        max_pixels: args.max_pixels,
+        resize_filter: args.resize_filter,
+        widths: args.widths.clone(),
+        variant_template: args.variant_template.clone(),
+        collision_name: args.collision_name.clone(),
+        ..Default::default()
+    };
+    if let Err(e) = overrides.validate() {
+        eprintln!("{} {}", style("Error:").red(), e);
+        return ExitCode::FAILURE;
     }
 
-    if !args.silent { println!("{}", style("Preparing tools...").cyan()); }
-    let (_tmp_dir, pq, oxi) = match get_png_tools() {
-        Ok(t) => t,
-        Err(e) => { eprintln!("{}", style(e).red()); return; }
+    let options = OptimizeOptions {
+        overrides,
+        config_files: !args.no_config,
+        replace: args.replace,
+        incremental: args.incremental,
+        cache_file: args.cache_file.clone(),
+        dry_run: args.dry_run,
+        backup: args.backup.clone(),
+        out_dir: args.out_dir.clone(),
+        non_images: args.non_images,
+        ignore_files: !args.no_ignore,
+        include: args.include.clone(),
+        exclude: args.exclude.clone(),
+        hidden: args.hidden,
+        max_depth: args.max_depth.map(|d| d as usize),
+        follow_links: args.follow_symlinks,
+        ..Default::default()
     };
 
-    let supported_exts = ["png", "jpg", "jpeg"];
-    let mut files_to_process: Vec<(PathBuf, PathBuf)> = Vec::new();
-    let mut copy_duration = Duration::new(0, 0);
-    let scan_start = Instant::now();
+    let optimizer = Optimizer::new(options);
 
-    let is_single_dir_mode = args.paths.len() == 1 && Path::new(&args.paths[0]).is_dir();
-
-    if is_single_dir_mode {
-        let input_path = PathBuf::from(&args.paths[0]);
-        let target_dir: PathBuf;
-
-        if args.replace {
-            target_dir = input_path.clone();
-            if !args.silent { 
-                println!("Mode: {} (Overwriting files in {})", style("REPLACE").red().bold(), style(target_dir.to_string_lossy()).cyan()); 
-            }
+    let plan = match optimizer.discover(&args.paths, |event| {
+        if ndjson {
+            print_event_line(event);
         } else {
-            let root_name = input_path.file_name().unwrap_or_default().to_string_lossy();
-            let new_name = format!("{}__optimized", root_name);
-            target_dir = input_path.parent().unwrap_or(Path::new(".")).join(new_name);
-            
-            if !args.silent { 
-                println!("Mode: {} (Copying to {})", style("SAFE").green().bold(), style(target_dir.to_string_lossy()).cyan()); 
-            }
-            let copy_start = Instant::now();
-            if let Err(e) = copy_dir_recursive(&input_path, &target_dir) {
-                eprintln!("{} {}", style("Error copying directory:").red(), e);
-                return;
-            }
-            copy_duration = copy_start.elapsed();
-            if !args.silent { println!("Copy complete in {}", style(format!("{:.2?}", copy_duration)).yellow()); }
-        }
-
-        if !args.silent { 
-            println!("Scanning directory: {}", style(target_dir.to_string_lossy()).cyan()); 
-        }
-        let scanned: Vec<(PathBuf, PathBuf)> = WalkDir::new(&target_dir)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|e| {
-                e.path().extension()
-                    .map(|ext| supported_exts.contains(&ext.to_string_lossy().to_lowercase().as_str()))
-                    .unwrap_or(false)
-            })
-            .map(|e| {
-                let p = e.into_path();
-                (p.clone(), p)
-            })
-            .collect();
-        files_to_process.extend(scanned);
-
-    } else {
-        if !args.silent { println!("Mode: {}", style("Specific File/Folder List Processing").magenta()); }
-        let copy_start = Instant::now();
-        
-        for p_str in &args.paths {
-            let path = Path::new(p_str);
-            if !path.exists() {
-                if !args.silent { eprintln!("{}", style(format!("Skipping not found: {:?}", path)).yellow()); }
-                continue;
-            }
-
-            if path.is_dir() {
-                let target_dir_root = if args.replace {
-                    path.to_path_buf()
-                } else {
-                    let root_name = path.file_name().unwrap_or_default().to_string_lossy();
-                    let new_name = format!("{}__optimized", root_name);
-                    path.parent().unwrap_or(Path::new(".")).join(new_name)
-                };
-
-                if !args.silent {
-                    println!("  > Processing Directory: {} -> {}", 
-                        style(path.file_name().unwrap_or_default().to_string_lossy()).cyan(),
-                        style(target_dir_root.file_name().unwrap_or_default().to_string_lossy()).yellow()
-                    );
-                }
-
-                if !args.replace {
-                    if let Err(e) = copy_dir_recursive(path, &target_dir_root) {
-                        eprintln!("{} {:?}: {}", style("Error copying directory").red(), path, e);
-                        continue;
-                    }
-                }
+            print_discovery_event(&args, event);
+        }
+    }) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{} {}", style("Error:").red(), e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-                let scanned: Vec<(PathBuf, PathBuf)> = WalkDir::new(&target_dir_root)
-                    .into_iter()
-                    .filter_map(|e| e.ok())
-                    .filter(|e| {
-                        e.path().extension()
-                            .map(|ext| supported_exts.contains(&ext.to_string_lossy().to_lowercase().as_str()))
-                            .unwrap_or(false)
-                    })
-                    .map(|e| {
-                        let p = e.into_path();
-                        (p.clone(), p)
-                    })
-                    .collect();
-                files_to_process.extend(scanned);
-                continue;
-            }
-            
-            let ext = path.extension().unwrap_or(OsStr::new("")).to_string_lossy().to_lowercase();
-            if !supported_exts.contains(&ext.as_str()) {
-                if !args.silent { eprintln!("{}", style(format!("Skipping unsupported type: {:?}", path)).yellow()); }
-                continue;
-            }
+    if ndjson {
+        println!("{}", serde_json::json!({ "event": "plan", "files": plan.files.len(), "cached": plan.cached.len() }));
+    }
 
-            let target_path = if args.replace {
-                path.to_path_buf()
-            } else {
-                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
-                let new_name = format!("{}__optimized.{}", stem, ext);
-                path.parent().unwrap_or(Path::new(".")).join(new_name)
-            };
+    if !plan.cached.is_empty() && !args.silent {
+        println!("Skipping: {} unchanged files (cache)", style(plan.cached.len()).bold().green());
+    }
+    if !plan.earlier_outputs.is_empty() && !args.silent {
+        println!("Skipping: {} WebP files written by an earlier run for other images", style(plan.earlier_outputs.len()).bold().green());
+    }
 
-            let naming_base = if args.replace {
-                target_path.clone()
+    if plan.files.is_empty() {
+        if !args.silent {
+            if plan.cached.is_empty() && plan.earlier_outputs.is_empty() {
+                println!("{}", style("No supported files found to process.").red());
             } else {
-                path.to_path_buf()
-            };
-
-            if !args.replace {
-                if let Err(e) = fs::copy(path, &target_path) {
-                    eprintln!("{} {:?}: {}", style("Error creating safe copy for").red(), path, e);
-                    continue;
-                }
+                println!("{}", style("All files are already optimized.").green());
             }
-            files_to_process.push((target_path, naming_base));
         }
-        
-        if !args.replace {
-            copy_duration = copy_start.elapsed();
+        if args.watch {
+            return run_watch(&args, &optimizer);
         }
+        return ExitCode::SUCCESS;
     }
 
-    let scan_duration = scan_start.elapsed();
+    // AVIF can't be decoded in-process, so there is nothing to measure a perceptual target on.
+    if plan.files.iter().any(|f| f.settings.avif && f.settings.quality_target.is_some()) {
+        eprintln!("{} --target-ssim/--target-dssim don't apply to AVIF outputs; they keep quality {} (or follow --max-bytes).", style("Warning:").yellow(), AVIF_QUALITY);
+    }
 
-    if files_to_process.is_empty() {
-        if !args.silent { println!("{}", style("No supported files found to process.").red()); }
-        return;
+    if plan.files.iter().any(|f| f.settings.avif) && !args.silent {
+        println!("{}", style("[!] WARNING: AVIF encoding is active.").red().bold());
+        println!("{}", style("    This process is extremely CPU intensive and may take significantly longer.").yellow());
+        println!("{}", style("    Ensure your system has adequate cooling and power.").yellow());
+        println!("{}", style("------------------------------------------------").dim());
     }
 
-    if !args.silent { println!("Found: {} files. Processing...", style(files_to_process.len()).bold().yellow()); }
-    
-    let bar = ProgressBar::new(files_to_process.len() as u64);
-    
+    if !args.silent { println!("Found: {} files. Processing...", style(plan.files.len()).bold().yellow()); }
+
+    let bar = if ndjson { ProgressBar::hidden() } else { ProgressBar::new(plan.files.len() as u64) };
+
     bar.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise:.bold}] [{bar:40.cyan/white}] {pos}/{len} ({eta}) {msg}")
         .unwrap()
         .tick_chars("|/-\\ ")
@@ -198,58 +156,17 @@ fn main() {
 
     bar.enable_steady_tick(Duration::from_millis(100));
 
-    let total_input_size = AtomicU64::new(0);
-    
-    let saved_orig = AtomicU64::new(0);
-    let saved_webp = AtomicU64::new(0);
-    let saved_avif = AtomicU64::new(0);
-
-    let time_jpg = AtomicU64::new(0);
-    let time_png = AtomicU64::new(0);
-    let time_webp = AtomicU64::new(0);
-    let time_avif = AtomicU64::new(0);
-
-    let process_start_time = Instant::now();
-
-    files_to_process.par_iter().for_each(|(path, naming_path)| {
-        let current_file_name = path.file_name().unwrap_or_default().to_string_lossy();
-        bar.set_message(format!("{}", style(current_file_name).dim()));
-
-        let ext = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
-        let original_file_size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
-        
-        total_input_size.fetch_add(original_file_size, Ordering::Relaxed);
-
-        if args.webp || args.avif {
-            if let Ok(img) = image::open(path) {
-                if args.webp {
-                    let t = Instant::now();
-                    let s = generate_webp(&img, naming_path, 75.0, original_file_size);
-                    time_webp.fetch_add(t.elapsed().as_millis() as u64, Ordering::Relaxed);
-                    saved_webp.fetch_add(s, Ordering::Relaxed);
-                }
-                if args.avif {
-                    let t = Instant::now();
-                    let s = generate_avif(&img, naming_path, original_file_size);
-                    time_avif.fetch_add(t.elapsed().as_millis() as u64, Ordering::Relaxed);
-                    saved_avif.fetch_add(s, Ordering::Relaxed);
-                }
-            }
+    let summary = optimizer.process(&plan, |event| match event {
+        _ if ndjson => print_event_line(event),
+        Event::FileStarted { path } => {
+            let current_file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            bar.set_message(format!("{}", style(current_file_name).dim()));
         }
-
-        let t_orig = Instant::now();
-        let s_orig = if ext == "png" {
-            let res = process_png(path, &pq, &oxi, args.png_min, args.png_max);
-            time_png.fetch_add(t_orig.elapsed().as_millis() as u64, Ordering::Relaxed);
-            res
-        } else {
-            let res = process_jpg(path, args.jpg_q);
-            time_jpg.fetch_add(t_orig.elapsed().as_millis() as u64, Ordering::Relaxed);
-            res
-        };
-        saved_orig.fetch_add(s_orig, Ordering::Relaxed);
-
-        bar.inc(1);
+        Event::FileFinished { .. } => bar.inc(1),
+        Event::CacheSaveFailed { path, error } => {
+            bar.suspend(|| eprintln!("{} {:?}: {}", style("Error saving cache").red(), path, error));
+        }
+        _ => {}
     });
 
     if args.silent {
@@ -258,65 +175,429 @@ fn main() {
         bar.finish_with_message(format!("{}", style("Done").green().bold()));
     }
 
-    let process_duration = process_start_time.elapsed();
     let total_duration = total_start_time.elapsed();
 
-    let total_in = total_input_size.load(Ordering::Relaxed);
-    let s_orig = saved_orig.load(Ordering::Relaxed);
-    let s_webp = saved_webp.load(Ordering::Relaxed);
-    let s_avif = saved_avif.load(Ordering::Relaxed);
+    let report = Report::new(&plan, &summary, args.dry_run, total_duration);
+    if ndjson {
+        // Per-file results were already streamed as `file_finished` events.
+        let mut line = serde_json::to_value(&report).unwrap_or_default();
+        if let Some(fields) = line.as_object_mut() {
+            fields.remove("files");
+        }
+        line["event"] = "summary".into();
+        println!("{}", line);
+    }
+    let mut report_failed = false;
+    if let Some(path) = &args.report
+        && let Err(e) = report.write(path) {
+        eprintln!("{} {:?}: {}", style("Error writing report").red(), path, e);
+        report_failed = true;
+    }
+    // The manifest lists files on disk, which a dry run doesn't write.
+    if let Some(path) = args.manifest.as_ref().filter(|_| !args.dry_run) {
+        let mut manifest = Manifest::load(path);
+        manifest.update(&summary);
+        if let Err(e) = manifest.write(path) {
+            eprintln!("{} {:?}: {}", style("Error writing manifest").red(), path, e);
+            report_failed = true;
+        }
+    }
 
-    let t_jpg = time_jpg.load(Ordering::Relaxed);
-    let t_png = time_png.load(Ordering::Relaxed);
-    let t_webp = time_webp.load(Ordering::Relaxed);
-    let t_avif = time_avif.load(Ordering::Relaxed);
+    let total_in = summary.total_input_size;
+    let s_orig = summary.saved_orig;
+    let s_webp = summary.saved_webp;
+    let s_avif = summary.saved_avif;
 
     if !args.silent {
-        println!("\n{}", style("=== Final Results ===").bold().magenta());
-        
+        if args.dry_run {
+            println!("\n{}", style("=== Projected Savings Per File ===").bold().magenta());
+            for file in &summary.files {
+                println!("    {}", style(file.path.to_string_lossy()).cyan());
+                if let Some(r) = file.resized {
+                    println!("      L Resize: {}x{} -> {}x{}", r.from.width, r.from.height, r.to.width, r.to.height);
+                }
+                for output in &file.outputs {
+                    let label = match output.variant {
+                        Some(v) => format!("{:?} {}w", output.kind, v.width),
+                        None => format!("{:?}", output.kind),
+                    };
+                    let tuned = output.tuned.map(describe_tuned).unwrap_or_default();
+                    match &output.error {
+                        Some(error) => println!("      L {}: {}", label, style(error).red()),
+                        None => println!("      L {}: {} -> {} (-{}){}",
+                            label,
+                            format_size(output.original_size, DECIMAL),
+                            style(format_size(output.new_size, DECIMAL)).green(),
+                            format_size(output.saved(), DECIMAL),
+                            style(tuned).dim()
+                        ),
+                    }
+                }
+            }
+        }
+
+        let heading = if args.dry_run { "=== Projected Results (dry run, nothing written) ===" } else { "=== Final Results ===" };
+        println!("\n{}", style(heading).bold().magenta());
+
         let calc_perc = |saved: u64| -> f64 {
             if total_in > 0 { (saved as f64 / total_in as f64) * 100.0 } else { 0.0 }
         };
 
         println!("    Total input size:    {}", style(format_size(total_in, DECIMAL)).cyan().bold());
         println!("    Total wall time:     {}", style(format!("{:.2?}", total_duration)).yellow());
-        if !args.replace {
-            println!("      L Copy/Prep time:   {}", style(format!("{:.2?}", copy_duration)).dim());
+        if !args.replace && !args.dry_run {
+            println!("      L Copy/Prep time:   {}", style(format!("{:.2?}", plan.copy_duration)).dim());
         }
-        if is_single_dir_mode {
-            println!("      L Scan time:        {}", style(format!("{:.2?}", scan_duration)).dim());
+        if plan.single_dir {
+            println!("      L Scan time:        {}", style(format!("{:.2?}", plan.scan_duration)).dim());
         }
-        println!("      L Processing time: {}", style(format!("{:.2?}", process_duration)).yellow());
+        println!("      L Processing time: {}", style(format!("{:.2?}", summary.process_duration)).yellow());
         println!("{}", style("    ------------------------------------------------").dim());
-        
-        println!("    Optimization (JPG/PNG): {} ({})", 
-            style(format_size(total_in - s_orig, DECIMAL)).green().bold(), 
+
+        println!("    Optimized Originals:    {} ({})",
+            style(format_size(total_in - s_orig, DECIMAL)).green().bold(),
             style(format!("-{:.1}%", calc_perc(s_orig))).green()
         );
-        if t_jpg > 0 { println!("      L JPG Cumulative Time: {:.2}s", t_jpg as f64 / 1000.0); }
-        if t_png > 0 { println!("      L PNG Cumulative Time: {:.2}s", t_png as f64 / 1000.0); }
-        
-        if args.webp {
-            println!("    WebP Generation:        {} ({})", 
-                style(format_size(total_in - s_webp, DECIMAL)).green().bold(), 
+        if !summary.time_jpg.is_zero() { println!("      L JPG Cumulative Time: {:.2}s", summary.time_jpg.as_secs_f64()); }
+        if !summary.time_png.is_zero() { println!("      L PNG Cumulative Time: {:.2}s", summary.time_png.as_secs_f64()); }
+        if !summary.time_other.is_zero() { println!("      L Other Formats Cumulative Time: {:.2}s", summary.time_other.as_secs_f64()); }
+
+        let missed = summary.files.iter()
+            .flat_map(|f| &f.outputs)
+            .filter(|o| o.tuned.is_some_and(|t| t.target_met == Some(false)))
+            .count();
+        if missed > 0 {
+            println!("    Quality target missed:  {} output(s), written at the highest allowed quality", style(missed).yellow());
+        }
+
+        let oversized: Vec<_> = summary.files.iter()
+            .flat_map(|f| &f.outputs)
+            .filter(|o| o.tuned.is_some_and(|t| t.fits == Some(false)))
+            .collect();
+        if !oversized.is_empty() {
+            println!("    Over --max-bytes:       {} output(s), even at the lowest quality", style(oversized.len()).yellow());
+            for output in oversized {
+                println!("      L {} ({})", style(output.path.to_string_lossy()).yellow(), format_size(output.new_size, DECIMAL));
+            }
+        }
+
+        let mismatched: Vec<_> = summary.files.iter().filter(|f| f.extension_mismatch).collect();
+        if !mismatched.is_empty() {
+            println!("    Wrong extension:        {} file(s), optimized by content", style(mismatched.len()).yellow());
+            for file in mismatched {
+                // Fixed files already carry the right extension.
+                let note = if ImageKind::from_path(&file.path) == Some(file.format) { "renamed" } else { "use --fix-extensions to rename" };
+                println!("      L {} ({}, {})", style(file.path.to_string_lossy()).yellow(), file.kind, style(note).dim());
+            }
+        }
+
+        let resized = summary.files.iter().filter(|f| f.resized.is_some()).count();
+        if resized > 0 {
+            println!("    Downscaled:             {} file(s)", style(resized).yellow());
+        }
+
+        if summary.has_output(OutputKind::WebP) {
+            println!("    WebP Generation:        {} ({})",
+                style(format_size(total_in - s_webp, DECIMAL)).green().bold(),
                 style(format!("-{:.1}%", calc_perc(s_webp))).green()
             );
-            println!("      L Cumulative Time:      {:.2}s", t_webp as f64 / 1000.0);
+            println!("      L Cumulative Time:      {:.2}s", summary.time_webp.as_secs_f64());
         }
-        
-        if args.avif {
-            println!("    AVIF Generation:        {} ({})", 
-                style(format_size(total_in - s_avif, DECIMAL)).green().bold(), 
+
+        if summary.has_output(OutputKind::Avif) {
+            println!("    AVIF Generation:        {} ({})",
+                style(format_size(total_in - s_avif, DECIMAL)).green().bold(),
                 style(format!("-{:.1}%", calc_perc(s_avif))).green()
             );
-            println!("      L Cumulative Time:      {:.2}s", t_avif as f64 / 1000.0);
+            println!("      L Cumulative Time:      {:.2}s", summary.time_avif.as_secs_f64());
+        }
+
+        if summary.variants > 0 {
+            println!("    Responsive Variants:    {} file(s)", style(summary.variants).green().bold());
+            println!("      L Cumulative Time:      {:.2}s", summary.time_variants.as_secs_f64());
+        }
+
+        let failed: Vec<_> = summary.failed().collect();
+        if !failed.is_empty() {
+            println!("{}", style("    ------------------------------------------------").dim());
+            println!("    {}", style(format!("Failed: {} file(s)", failed.len())).red().bold());
+            for file in &failed {
+                for output in file.errors() {
+                    if let Some(error) = &output.error {
+                        println!("      L {} [{:?}]: {}", style(file.path.to_string_lossy()).red(), output.kind, error);
+                    }
+                }
+            }
+        }
+
+        if let Some(dir) = optimizer.backup().and_then(|b| b.run_dir()) {
+            println!("{}", style("    ------------------------------------------------").dim());
+            println!("    Backup:                 {}", style(dir.to_string_lossy()).cyan());
+            println!("      L Restore with:       images-optimizer undo {}{}",
+                dir.file_name().unwrap_or_default().to_string_lossy(),
+                match &args.backup {
+                    Some(root) if root != Path::new(backup::BACKUP_DIR_NAME) => format!(" --backup {}", root.to_string_lossy()),
+                    _ => String::new(),
+                }
+            );
         }
-        
+
         println!("\n{}", style("    * Note: 'Cumulative Time' represents the sum of work across all CPU cores.").dim().italic());
         println!("{}", style("      It differs from 'Wall time' due to parallel processing.").dim().italic());
+    } else {
+        for file in summary.failed() {
+            for output in file.errors() {
+                if let Some(error) = &output.error {
+                    eprintln!("{:?} [{:?}]: {}", file.path, output.kind, error);
+                }
+            }
+        }
+    }
+
+    if args.watch {
+        return run_watch(&args, &optimizer);
+    }
 
+    if should_pause(&args) {
         println!("\nPress any key to exit...");
         let term = Term::stdout();
         let _ = term.read_char();
     }
-}
\ No newline at end of file
+
+    if summary.has_failures() || report_failed { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
+
+fn run_undo(args: &UndoArgs) -> ExitCode {
+    if args.list {
+        return match backup::list_runs(&args.backup) {
+            Ok(runs) if runs.is_empty() => {
+                println!("No runs in {}", args.backup.to_string_lossy());
+                ExitCode::SUCCESS
+            }
+            Ok(runs) => {
+                for run in runs {
+                    println!("{}  {} file(s)", style(run.id).cyan(), run.files);
+                }
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("{} {:?}: {}", style("Error reading backups").red(), args.backup, e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let summary = match backup::undo(&args.backup, args.run.as_deref(), args.force) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{} {}", style("Error:").red(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("Undoing run {}", style(&summary.run).cyan().bold());
+    println!("    Restored: {} file(s)", style(summary.restored.len()).green());
+    println!("    Deleted:  {} file(s) created by the run", style(summary.deleted.len()).green());
+    if !summary.conflicts.is_empty() {
+        println!("    {}", style(format!("Changed since the run, left alone: {} file(s) (use --force to restore anyway)", summary.conflicts.len())).yellow());
+        for path in &summary.conflicts {
+            println!("      L {}", style(path.to_string_lossy()).yellow());
+        }
+    }
+    for (path, error) in &summary.errors {
+        eprintln!("{} {:?}: {}", style("Error restoring").red(), path, error);
+    }
+    if summary.conflicts.is_empty() && summary.errors.is_empty() {
+        println!("{}", style("Run fully reverted; its backup was removed.").green());
+        ExitCode::SUCCESS
+    } else {
+        println!("{}", style("The backup of this run was kept.").dim());
+        ExitCode::FAILURE
+    }
+}
+
+fn run_watch(args: &Args, optimizer: &Optimizer) -> ExitCode {
+    catch_ctrl_c();
+    let result = optimizer.watch(Path::new(&args.paths[0]), &STOP, |event| match event {
+        _ if args.progress_format == ProgressFormat::Ndjson => print_event_line(event),
+        Event::Watching { dir } if !args.silent => {
+            println!("\n{} {} {}", style("Watching").cyan().bold(), style(dir.to_string_lossy()).cyan(), style("(Ctrl+C to stop)").dim());
+        }
+        Event::FileFinished { result } => {
+            for output in &result.outputs {
+                match &output.error {
+                    Some(error) => eprintln!("  {} {} [{:?}]: {}", style("x").red(), output.path.to_string_lossy(), output.kind, error),
+                    None if !args.silent => println!("  {} {} ({} -> {})",
+                        style("+").green(),
+                        output.path.to_string_lossy(),
+                        format_size(output.original_size, DECIMAL),
+                        style(format_size(output.new_size, DECIMAL)).green()
+                    ),
+                    None => {}
+                }
+            }
+        }
+        Event::CopyFailed { path, error } => eprintln!("{} {:?}: {}", style("Error copying").red(), path, error),
+        Event::CacheSaveFailed { path, error } => eprintln!("{} {:?}: {}", style("Error saving cache").red(), path, error),
+        Event::NameCollision { collision } if !args.silent => print_collision(collision),
+        Event::EarlierOutput { path } if !args.silent => {
+            println!("  {} {} (WebP version of another image)", style("-").dim(), path.to_string_lossy());
+        }
+        Event::WatchError { error } => eprintln!("{} {}", style("Watch error:").red(), error),
+        _ => {}
+    });
+    match result {
+        Ok(()) => {
+            if !args.silent && args.progress_format != ProgressFormat::Ndjson {
+                println!("{}", style("Stopped watching.").dim());
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{} {}", style("Watch error:").red(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The pause keeps the console window open when the tool was started by double-clicking
+/// it on Windows. Anywhere else it would only block scripts.
+fn should_pause(args: &Args) -> bool {
+    if args.pause {
+        return true;
+    }
+    !args.no_pause
+        && !args.silent
+        && io::stdin().is_terminal()
+        && io::stdout().is_terminal()
+        && owns_console()
+}
+
+/// Set by the first Ctrl+C, so watch mode finishes the batch it is on and returns.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Makes the first Ctrl+C set `STOP`. A second one ends the process as usual.
+#[cfg(not(target_os = "windows"))]
+fn catch_ctrl_c() {
+    const SIGINT: i32 = 2;
+    const SIG_DFL: usize = 0;
+    unsafe extern "C" {
+        fn signal(signum: i32, handler: usize) -> usize;
+    }
+    extern "C" fn on_sigint(_: i32) {
+        STOP.store(true, Ordering::Relaxed);
+        unsafe { signal(SIGINT, SIG_DFL) };
+    }
+    unsafe { signal(SIGINT, on_sigint as extern "C" fn(i32) as usize) };
+}
+
+#[cfg(target_os = "windows")]
+fn catch_ctrl_c() {
+    const CTRL_C_EVENT: u32 = 0;
+    const CTRL_BREAK_EVENT: u32 = 1;
+    unsafe extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<unsafe extern "system" fn(u32) -> i32>, add: i32) -> i32;
+    }
+    unsafe extern "system" fn on_ctrl(kind: u32) -> i32 {
+        let first = matches!(kind, CTRL_C_EVENT | CTRL_BREAK_EVENT) && !STOP.swap(true, Ordering::Relaxed);
+        first as i32
+    }
+    unsafe { SetConsoleCtrlHandler(Some(on_ctrl), 1) };
+}
+
+/// True when no other process (such as a shell) shares our console, i.e. Windows created it
+/// just for us because we were launched from Explorer.
+#[cfg(target_os = "windows")]
+fn owns_console() -> bool {
+    unsafe extern "system" {
+        fn GetConsoleProcessList(process_list: *mut u32, count: u32) -> u32;
+    }
+    let mut pids = [0u32; 2];
+    unsafe { GetConsoleProcessList(pids.as_mut_ptr(), pids.len() as u32) == 1 }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn owns_console() -> bool {
+    false
+}
+
+/// Dry-run label for a searched quality, e.g. ` [q62, ssim 0.9812, 800x600]`.
+fn describe_tuned(t: Tuned) -> String {
+    let mut parts = vec![format!("q{}", t.quality)];
+    if let Some(ssim) = t.ssim {
+        parts.push(format!("ssim {:.4}", ssim));
+    }
+    if let Some(d) = t.scaled_to {
+        parts.push(format!("{}x{}", d.width, d.height));
+    }
+    if t.target_met == Some(false) {
+        parts.push("target missed".to_string());
+    }
+    if t.fits == Some(false) {
+        parts.push("over max-bytes".to_string());
+    }
+    format!(" [{}]", parts.join(", "))
+}
+
+fn print_event_line(event: &Event) {
+    if let Some(line) = event_line(event) {
+        println!("{}", line);
+    }
+}
+
+fn print_discovery_event(args: &Args, event: &Event) {
+    if args.silent {
+        if let Event::CopyFailed { path, error } = event {
+            eprintln!("{} {:?}: {}", style("Error copying").red(), path, error);
+        }
+        return;
+    }
+    match event {
+        Event::DirectoryMode { replace, target } if args.dry_run => {
+            let action = if *replace { "would overwrite files in" } else if args.out_dir.is_some() { "would write to" } else { "would copy to" };
+            println!("Mode: {} ({} {})", style("DRY RUN").yellow().bold(), action, style(target.to_string_lossy()).cyan());
+        }
+        Event::DirectoryMode { replace: true, target } => {
+            println!("Mode: {} (Overwriting files in {})", style("REPLACE").red().bold(), style(target.to_string_lossy()).cyan());
+        }
+        Event::DirectoryMode { replace: false, target } if args.out_dir.is_some() => {
+            println!("Mode: {} (Writing to {})", style("OUT-DIR").green().bold(), style(target.to_string_lossy()).cyan());
+        }
+        Event::DirectoryMode { replace: false, target } => {
+            println!("Mode: {} (Copying to {})", style("SAFE").green().bold(), style(target.to_string_lossy()).cyan());
+        }
+        Event::CopyFinished { duration } => {
+            println!("Copy complete in {}", style(format!("{:.2?}", duration)).yellow());
+        }
+        Event::Scanning { dir } => {
+            println!("Scanning directory: {}", style(dir.to_string_lossy()).cyan());
+        }
+        Event::ListMode => {
+            println!("Mode: {}", style("Specific File/Folder List Processing").magenta());
+        }
+        Event::ListDirectory { source, target } => {
+            println!("  > Processing Directory: {} -> {}",
+                style(source.file_name().unwrap_or_default().to_string_lossy()).cyan(),
+                style(target.file_name().unwrap_or_default().to_string_lossy()).yellow()
+            );
+        }
+        Event::NotFound { path } => {
+            eprintln!("{}", style(format!("Skipping not found: {:?}", path)).yellow());
+        }
+        Event::Unsupported { path } => {
+            eprintln!("{}", style(format!("Skipping unsupported type: {:?}", path)).yellow());
+        }
+        Event::CopyFailed { path, error } => {
+            eprintln!("{} {:?}: {}", style("Error copying").red(), path, error);
+        }
+        Event::NameCollision { collision } => print_collision(collision),
+        _ => {}
+    }
+}
+
+fn print_collision(collision: &NameCollision) {
+    println!("{} {} would be written by {} files:", style("Name collision:").yellow(), style(collision.path.to_string_lossy()).cyan(), collision.sources.len());
+    for (source, output) in collision.sources.iter().zip(&collision.outputs) {
+        let note = if *output == collision.path { "keeps it".to_string() } else { format!("-> {}", output.file_name().unwrap_or_default().to_string_lossy()) };
+        println!("      L {} {}", source.to_string_lossy(), style(note).dim());
+    }
+}
@@ -0,0 +1,1245 @@
+use serde::Deserialize;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::error::OptimizeError;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+const XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_EXTENSION_PREFIX: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+/// Property of the main XMP packet naming the extended part by the MD5 of its content.
+const HAS_EXTENDED_XMP: &str = "xmpNote:HasExtendedXMP";
+const ICC_PREFIX: &[u8] = b"ICC_PROFILE\0";
+const IPTC_PREFIX: &[u8] = b"Photoshop 3.0\0";
+const XMP_KEYWORD: &str = "XML:com.adobe.xmp";
+/// Largest payload of one JPEG marker segment.
+const JPEG_SEGMENT_MAX: usize = 65533;
+/// Extended XMP carried by one segment, after the prefix, the 32-digit GUID, full length and offset.
+const XMP_EXTENSION_CHUNK: usize = JPEG_SEGMENT_MAX - XMP_EXTENSION_PREFIX.len() - 32 - 8;
+
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_ARTIST: u16 = 0x013B;
+const TAG_COPYRIGHT: u16 = 0x8298;
+const TAG_GPS_IFD: u16 = 0x8825;
+const TAG_XMP: u16 = 0x02BC;
+const TAG_ICC: u16 = 0x8773;
+/// PNG text keywords kept by `keep-copyright`.
+const COPYRIGHT_KEYWORDS: [&str; 3] = ["Copyright", "Author", "Artist"];
+
+/// What happens to EXIF, ICC, XMP, IPTC and text metadata of the source. Several policies
+/// combine: everything any of them keeps is kept, and `strip-gps` always removes GPS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetadataPolicy {
+    #[default]
+    StripAll,
+    KeepAll,
+    KeepIcc,
+    /// Only the artist and copyright fields.
+    KeepCopyright,
+    /// Everything except GPS location.
+    StripGps,
+}
+
+impl MetadataPolicy {
+    pub const NAMES: [&'static str; 5] = ["strip-all", "keep-all", "keep-icc", "keep-copyright", "strip-gps"];
+
+    /// What `policies` together keep.
+    pub fn keep(policies: &[MetadataPolicy]) -> Keep {
+        let mut keep = Keep::default();
+        for policy in policies {
+            match policy {
+                MetadataPolicy::StripAll => {}
+                MetadataPolicy::KeepAll => keep = Keep { icc: true, level: KeepLevel::All },
+                MetadataPolicy::KeepIcc => keep.icc = true,
+                MetadataPolicy::KeepCopyright => keep.level = keep.level.max(KeepLevel::Copyright),
+                MetadataPolicy::StripGps => keep = Keep { icc: true, level: keep.level.max(KeepLevel::NoGps) },
+            }
+        }
+        if policies.contains(&MetadataPolicy::StripGps) {
+            keep.level = keep.level.min(KeepLevel::NoGps);
+        }
+        keep
+    }
+}
+
+impl FromStr for MetadataPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Self::NAMES.iter().position(|n| n.eq_ignore_ascii_case(s)) {
+            Some(0) => Ok(MetadataPolicy::StripAll),
+            Some(1) => Ok(MetadataPolicy::KeepAll),
+            Some(2) => Ok(MetadataPolicy::KeepIcc),
+            Some(3) => Ok(MetadataPolicy::KeepCopyright),
+            Some(4) => Ok(MetadataPolicy::StripGps),
+            _ => Err(format!("unknown metadata policy '{}', expected one of: {}", s, Self::NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for MetadataPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+/// How much of EXIF, XMP, IPTC and text is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum KeepLevel {
+    #[default]
+    None,
+    Copyright,
+    NoGps,
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Keep {
+    pub icc: bool,
+    pub level: KeepLevel,
+}
+
+/// Metadata of a source image, independent of its container.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub icc: Option<Vec<u8>>,
+    /// TIFF-structured EXIF, without the JPEG `Exif\0\0` prefix.
+    pub exif: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+    /// The extended part of a JPEG's XMP, split off because it didn't fit in one segment. Only
+    /// written back to JPEG.
+    pub xmp_extended: Option<Vec<u8>>,
+    /// JPEG APP13 (Photoshop/IPTC) payload. Only written back to JPEG.
+    pub iptc: Option<Vec<u8>>,
+    /// PNG text chunks as `(keyword, text)`.
+    pub text: Vec<(String, String)>,
+}
+
+impl Metadata {
+    /// Reads the metadata of a JPEG, PNG, WebP or TIFF file. Anything unreadable is left out.
+    pub fn read(data: &[u8]) -> Metadata {
+        if data.starts_with(&[0xFF, 0xD8]) {
+            read_jpeg(data)
+        } else if data.starts_with(PNG_SIGNATURE) {
+            read_png(data)
+        } else if is_webp(data) {
+            read_webp(data)
+        } else if Tiff::new(data).is_some() {
+            read_tiff(data)
+        } else {
+            Metadata::default()
+        }
+    }
+
+    /// EXIF orientation, 1 to 8. `None` when absent or invalid.
+    pub fn orientation(&self) -> Option<u16> {
+        let exif = self.exif.as_deref()?;
+        let tiff = Tiff::new(exif)?;
+        let entry = tiff.find(tiff.ifd0()?, TAG_ORIENTATION)?;
+        tiff.u16(entry + 8).filter(|o| (1..=8).contains(o))
+    }
+
+    /// Marks the EXIF orientation as upright, for pixels that were rotated already.
+    pub fn reset_orientation(&mut self) {
+        let Some(exif) = self.exif.as_mut() else { return };
+        let value = {
+            let Some(tiff) = Tiff::new(exif) else { return };
+            let Some(entry) = tiff.ifd0().and_then(|ifd0| tiff.find(ifd0, TAG_ORIENTATION)) else { return };
+            let one = if tiff.big_endian { 1u16.to_be_bytes() } else { 1u16.to_le_bytes() };
+            (entry + 8, one)
+        };
+        exif[value.0..value.0 + 2].copy_from_slice(&value.1);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+
+    /// The part of this metadata that `keep` retains.
+    pub fn filter(&self, keep: Keep) -> Metadata {
+        let icc = self.icc.clone().filter(|_| keep.icc);
+        match keep.level {
+            KeepLevel::None => Metadata { icc, ..Default::default() },
+            KeepLevel::Copyright => Metadata {
+                icc,
+                exif: self.exif.as_deref().and_then(exif_copyright_only),
+                text: self.text.iter().filter(|(k, _)| COPYRIGHT_KEYWORDS.contains(&k.as_str())).cloned().collect(),
+                ..Default::default()
+            },
+            KeepLevel::NoGps => Metadata {
+                icc,
+                exif: self.exif.clone().map(|mut exif| {
+                    exif_strip_gps(&mut exif);
+                    exif
+                }),
+                xmp: self.xmp.as_deref().map(xmp_strip_gps),
+                xmp_extended: self.xmp_extended.as_deref().map(xmp_strip_gps),
+                ..self.clone()
+            },
+            KeepLevel::All => Metadata { icc, ..self.clone() },
+        }
+    }
+
+    /// EXIF to write into formats without text chunks: the source EXIF or, for PNG sources,
+    /// one built from their copyright and author text.
+    fn exif_for_export(&self) -> Option<Vec<u8>> {
+        if self.exif.is_some() {
+            return self.exif.clone();
+        }
+        let find = |keys: &[&str]| self.text.iter().find(|(k, _)| keys.contains(&k.as_str())).map(|(_, v)| v.as_bytes());
+        let mut entries = Vec::new();
+        if let Some(artist) = find(&["Author", "Artist"]) {
+            entries.push((TAG_ARTIST, artist));
+        }
+        if let Some(copyright) = find(&["Copyright"]) {
+            entries.push((TAG_COPYRIGHT, copyright));
+        }
+        (!entries.is_empty()).then(|| build_exif(&entries))
+    }
+
+    /// Replaces the metadata segments of a JPEG with this metadata. XMP too large for one segment
+    /// is written as extended XMP; EXIF or IPTC that is too large is an error.
+    pub fn apply_jpeg(&self, data: &[u8]) -> Result<Vec<u8>, OptimizeError> {
+        let segments = jpeg_segments(data).ok_or_else(|| OptimizeError::Encode("malformed JPEG".into()))?;
+        let body_start = segments.last().map_or(2, |s| s.end);
+
+        let mut out = Vec::with_capacity(data.len());
+        out.extend_from_slice(&data[..2]);
+        let mut rest = segments.iter().filter(|s| !is_metadata_segment(data, s)).peekable();
+        // JFIF must stay first.
+        if let Some(jfif) = rest.next_if(|s| data[s.start + 1] == 0xE0) {
+            out.extend_from_slice(&data[jfif.clone()]);
+        }
+        if let Some(exif) = self.exif_for_export() {
+            push_jpeg_segment(&mut out, 0xE1, &[EXIF_PREFIX, &exif], "EXIF")?;
+        }
+        if let Some(xmp) = &self.xmp {
+            push_jpeg_xmp(&mut out, xmp, self.xmp_extended.as_deref())?;
+        }
+        if let Some(icc) = &self.icc {
+            let chunks: Vec<&[u8]> = icc.chunks(JPEG_SEGMENT_MAX - ICC_PREFIX.len() - 2).collect();
+            for (i, chunk) in chunks.iter().enumerate() {
+                push_jpeg_segment(&mut out, 0xE2, &[ICC_PREFIX, &[i as u8 + 1, chunks.len() as u8], chunk], "ICC")?;
+            }
+        }
+        if let Some(iptc) = &self.iptc {
+            push_jpeg_segment(&mut out, 0xED, &[iptc], "IPTC")?;
+        }
+        for segment in rest {
+            out.extend_from_slice(&data[segment.clone()]);
+        }
+        out.extend_from_slice(&data[body_start..]);
+        Ok(out)
+    }
+
+    /// Replaces the metadata chunks of a PNG with this metadata.
+    pub fn apply_png(&self, data: &[u8]) -> Result<Vec<u8>, OptimizeError> {
+        let chunks = png_chunks(data).ok_or_else(|| OptimizeError::Encode("malformed PNG".into()))?;
+        let mut out = Vec::with_capacity(data.len());
+        out.extend_from_slice(PNG_SIGNATURE);
+        for (i, (name, range)) in chunks.iter().enumerate() {
+            let drop = matches!(name, b"iCCP" | b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt")
+                || (name == b"sRGB" && self.icc.is_some());
+            if !drop {
+                out.extend_from_slice(&data[range.clone()]);
+            }
+            if i > 0 {
+                continue;
+            }
+            // Right after IHDR: iCCP has to precede PLTE and IDAT.
+            if let Some(icc) = &self.icc {
+                let compressed = miniz_oxide::deflate::compress_to_vec_zlib(icc, 9);
+                push_png_chunk(&mut out, b"iCCP", &[b"ICC Profile\0\0", &compressed]);
+            }
+            if let Some(exif) = &self.exif {
+                push_png_chunk(&mut out, b"eXIf", &[exif]);
+            }
+            if let Some(xmp) = &self.xmp {
+                push_png_chunk(&mut out, b"iTXt", &[XMP_KEYWORD.as_bytes(), b"\0\0\0\0\0", xmp]);
+            }
+            for (keyword, text) in &self.text {
+                push_png_chunk(&mut out, b"iTXt", &[keyword.as_bytes(), b"\0\0\0\0\0", text.as_bytes()]);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Replaces the metadata chunks of a WebP with this metadata, switching to the extended
+    /// (`VP8X`) layout when there is any. Animations always keep that layout.
+    pub fn apply_webp(&self, data: &[u8]) -> Result<Vec<u8>, OptimizeError> {
+        let malformed = || OptimizeError::Encode("malformed WebP".into());
+        let chunks = riff_chunks(data).ok_or_else(malformed)?;
+        let exif = self.exif_for_export();
+
+        let mut canvas = None;
+        let mut alpha = false;
+        let mut animated = false;
+        let mut image = Vec::new();
+        for (name, range) in &chunks {
+            let body = &data[range.start + 8..range.end.min(range.start + 8 + chunk_len(data, range.start))];
+            match name {
+                b"VP8X" if body.len() >= 10 => {
+                    alpha = body[0] & 0x10 != 0;
+                    animated = body[0] & 0x02 != 0;
+                    canvas = Some((u24(&body[4..7]) + 1, u24(&body[7..10]) + 1));
+                }
+                b"ICCP" | b"EXIF" | b"XMP " | b"VP8X" => {}
+                _ => {
+                    if name == b"ALPH" {
+                        alpha = true;
+                    }
+                    if matches!(name, b"ANIM" | b"ANMF") {
+                        animated = true;
+                    }
+                    if canvas.is_none() {
+                        canvas = webp_frame_size(name, body);
+                    }
+                    image.extend_from_slice(&data[range.clone()]);
+                }
+            }
+        }
+        let (width, height) = canvas.ok_or_else(malformed)?;
+        if self.icc.is_none() && exif.is_none() && self.xmp.is_none() && !alpha && !animated {
+            return Ok(riff_file(&image));
+        }
+
+        let mut flags = 0u8;
+        if self.icc.is_some() { flags |= 0x20; }
+        if alpha { flags |= 0x10; }
+        if exif.is_some() { flags |= 0x08; }
+        if self.xmp.is_some() { flags |= 0x04; }
+        if animated { flags |= 0x02; }
+        let mut vp8x = vec![flags, 0, 0, 0];
+        vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+        let mut body = Vec::new();
+        push_riff_chunk(&mut body, b"VP8X", &vp8x);
+        if let Some(icc) = &self.icc {
+            push_riff_chunk(&mut body, b"ICCP", icc);
+        }
+        body.extend_from_slice(&image);
+        if let Some(exif) = &exif {
+            push_riff_chunk(&mut body, b"EXIF", exif);
+        }
+        if let Some(xmp) = &self.xmp {
+            push_riff_chunk(&mut body, b"XMP ", xmp);
+        }
+        Ok(riff_file(&body))
+    }
+
+    /// Attaches EXIF and ICC to a ravif encoding. AVIF carries no XMP.
+    pub fn apply_avif(&self, encoded: ravif::EncodedImage) -> Result<Vec<u8>, OptimizeError> {
+        let mut file = match self.exif_for_export() {
+            Some(exif) => avif_with_exif(&encoded, &exif)?,
+            None => encoded.avif_file,
+        };
+        if let Some(icc) = &self.icc {
+            avif_add_icc(&mut file, icc).ok_or_else(|| OptimizeError::Encode("unexpected AVIF layout, can't embed ICC".into()))?;
+        }
+        Ok(file)
+    }
+}
+
+/// Re-serializes the AV1 payload of a ravif encoding with an EXIF item, which ravif can't add.
+fn avif_with_exif(encoded: &ravif::EncodedImage, exif: &[u8]) -> Result<Vec<u8>, OptimizeError> {
+    let file = &encoded.avif_file;
+    let payload = encoded.color_byte_size + encoded.alpha_byte_size;
+    let header_len = file.len().checked_sub(payload).ok_or_else(|| OptimizeError::Encode("malformed AVIF".into()))?;
+    let (header, av1) = file.split_at(header_len);
+    // avif-serialize stores alpha before color in `mdat`.
+    let (alpha, color) = av1.split_at(encoded.alpha_byte_size);
+    let depth = find(header, b"pixi").and_then(|i| header.get(i + 9).copied()).unwrap_or(8);
+    let (width, height) = find(header, b"ispe")
+        .and_then(|i| Some((be_u32(header.get(i + 8..i + 12)?), be_u32(header.get(i + 12..i + 16)?))))
+        .ok_or_else(|| OptimizeError::Encode("malformed AVIF".into()))?;
+
+    // The HEIF Exif item starts with the offset of the TIFF header.
+    let mut item = vec![0, 0, 0, 0];
+    item.extend_from_slice(exif);
+    Ok(avif_serialize::Aviffy::new()
+        // ravif's default YCbCr model.
+        .matrix_coefficients(avif_serialize::constants::MatrixCoefficients::Bt601)
+        .set_exif(item)
+        .to_vec(color, (!alpha.is_empty()).then_some(alpha), width, height, depth))
+}
+
+/// Adds a `colr` box of type `prof` to the primary item of an AVIF written by avif-serialize,
+/// which only writes `nclx` ones. `None` when the file doesn't have the expected layout.
+fn avif_add_icc(file: &mut Vec<u8>, icc: &[u8]) -> Option<()> {
+    let meta = isobmff_children(file, 0..file.len())?.into_iter().find(|b| &b.0 == b"meta")?;
+    let children = isobmff_children(file, meta.1.start + 12..meta.1.end)?;
+    let child = |name: &[u8; 4]| children.iter().find(|b| &b.0 == name).map(|b| b.1.clone());
+    let (iloc, iprp) = (child(b"iloc")?, child(b"iprp")?);
+    let primary = match file.get(child(b"pitm")?.start + 8)? {
+        0 => be_u16(file.get(child(b"pitm")?.start + 12..)?) as u32,
+        _ => be_u32(file.get(child(b"pitm")?.start + 12..)?),
+    };
+    let props = isobmff_children(file, iprp.start + 8..iprp.end)?;
+    let ipco = props.iter().find(|b| &b.0 == b"ipco")?.1.clone();
+    let ipma = props.iter().find(|b| &b.0 == b"ipma")?.1.clone();
+    let index = isobmff_children(file, ipco.start + 8..ipco.end)?.len() + 1;
+
+    let mut colr = Vec::with_capacity(icc.len() + 12);
+    colr.extend_from_slice(&(icc.len() as u32 + 12).to_be_bytes());
+    colr.extend_from_slice(b"colrprof");
+    colr.extend_from_slice(icc);
+    let grow = colr.len() + 1;
+
+    // Item data moves by `grow`; shift every absolute offset in iloc.
+    let version = file[iloc.start + 8];
+    let sizes = (file[iloc.start + 12] >> 4, file[iloc.start + 12] & 0xF, file[iloc.start + 13] >> 4, file[iloc.start + 13] & 0xF);
+    let (offset_size, length_size, base_size) = (sizes.0 as usize, sizes.1 as usize, sizes.2 as usize);
+    let index_size = if version >= 1 { sizes.3 as usize } else { 0 };
+    let id_size = if version < 2 { 2 } else { 4 };
+    let mut pos = iloc.start + 14;
+    let count = read_be(file, pos, id_size)?;
+    pos += id_size;
+    for _ in 0..count {
+        pos += id_size + if version >= 1 { 2 } else { 0 } + 2;
+        let shift_at = |file: &mut Vec<u8>, at: usize, size: usize| -> Option<()> {
+            if size > 0 {
+                let value = read_be(file, at, size)? + grow as u64;
+                file.get_mut(at..at + size)?.copy_from_slice(&value.to_be_bytes()[8 - size..]);
+            }
+            Some(())
+        };
+        let has_base = base_size > 0 && read_be(file, pos, base_size)? > 0;
+        if has_base {
+            shift_at(file, pos, base_size)?;
+        }
+        pos += base_size;
+        let extents = read_be(file, pos, 2)?;
+        pos += 2;
+        for _ in 0..extents {
+            pos += index_size;
+            if !has_base {
+                shift_at(file, pos, offset_size)?;
+            }
+            pos += offset_size + length_size;
+        }
+    }
+
+    // Associate the new property with the primary item.
+    let ipma_version = file[ipma.start + 8];
+    let wide = file[ipma.start + 11] & 1 == 1;
+    if wide || index > 0x7F {
+        return None;
+    }
+    let item_size = if ipma_version < 1 { 2 } else { 4 };
+    let mut pos = ipma.start + 16;
+    let mut insert_at = None;
+    for _ in 0..read_be(file, ipma.start + 12, 4)? {
+        let item = read_be(file, pos, item_size)?;
+        let associations = *file.get(pos + item_size)? as usize;
+        if item == primary as u64 {
+            file[pos + item_size] += 1;
+            insert_at = Some(pos + item_size + 1 + associations);
+            break;
+        }
+        pos += item_size + 1 + associations;
+    }
+    file.insert(insert_at?, index as u8);
+    for (start, extra) in [(ipma.start, 1), (ipco.start, colr.len()), (iprp.start, grow), (meta.1.start, grow)] {
+        let size = be_u32(&file[start..]) as usize + extra;
+        file[start..start + 4].copy_from_slice(&(size as u32).to_be_bytes());
+    }
+    file.splice(ipco.end..ipco.end, colr);
+    Some(())
+}
+
+/// Boxes directly inside `range`, with ranges covering their header and body.
+fn isobmff_children(data: &[u8], range: Range<usize>) -> Option<Vec<([u8; 4], Range<usize>)>> {
+    let mut boxes = Vec::new();
+    let mut pos = range.start;
+    while pos + 8 <= range.end {
+        let size = be_u32(data.get(pos..pos + 4)?) as usize;
+        if size < 8 || pos + size > range.end {
+            return None;
+        }
+        boxes.push((data[pos + 4..pos + 8].try_into().ok()?, pos..pos + size));
+        pos += size;
+    }
+    Some(boxes)
+}
+
+fn read_be(data: &[u8], pos: usize, size: usize) -> Option<u64> {
+    Some(data.get(pos..pos + size)?.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64))
+}
+
+fn read_jpeg(data: &[u8]) -> Metadata {
+    let mut meta = Metadata::default();
+    let mut icc_chunks: Vec<(u8, &[u8])> = Vec::new();
+    let mut extensions: Vec<&[u8]> = Vec::new();
+    for segment in jpeg_segments(data).unwrap_or_default() {
+        let payload = &data[segment.start + 4..segment.end];
+        match data[segment.start + 1] {
+            0xE1 if payload.starts_with(EXIF_PREFIX) => meta.exif = Some(payload[EXIF_PREFIX.len()..].to_vec()),
+            0xE1 if payload.starts_with(XMP_PREFIX) => meta.xmp = Some(payload[XMP_PREFIX.len()..].to_vec()),
+            0xE1 if payload.starts_with(XMP_EXTENSION_PREFIX) => extensions.push(&payload[XMP_EXTENSION_PREFIX.len()..]),
+            0xE2 if payload.starts_with(ICC_PREFIX) && payload.len() > ICC_PREFIX.len() + 2 => {
+                icc_chunks.push((payload[ICC_PREFIX.len()], &payload[ICC_PREFIX.len() + 2..]));
+            }
+            0xED if payload.starts_with(IPTC_PREFIX) => meta.iptc = Some(payload.to_vec()),
+            _ => {}
+        }
+    }
+    if !icc_chunks.is_empty() {
+        icc_chunks.sort_by_key(|(seq, _)| *seq);
+        meta.icc = Some(icc_chunks.into_iter().flat_map(|(_, c)| c.iter().copied()).collect());
+    }
+    if let Some(guid) = meta.xmp.as_deref().and_then(extended_xmp_guid) {
+        meta.xmp_extended = join_extended_xmp(&extensions, guid);
+    }
+    meta
+}
+
+/// GUID of the extended XMP the main packet `xmp` points to.
+fn extended_xmp_guid(xmp: &[u8]) -> Option<&[u8]> {
+    let at = find(xmp, HAS_EXTENDED_XMP.as_bytes())? + HAS_EXTENDED_XMP.len();
+    let start = at + xmp.get(at..)?.iter().position(|b| b.is_ascii_hexdigit())?;
+    xmp.get(start..start + 32).filter(|guid| guid.iter().all(u8::is_ascii_hexdigit))
+}
+
+/// Reassembles the extension segments with `guid`. `None` unless they cover the whole of it.
+fn join_extended_xmp(extensions: &[&[u8]], guid: &[u8]) -> Option<Vec<u8>> {
+    let parts: Vec<&[u8]> = extensions.iter().copied().filter(|e| e.starts_with(guid)).collect();
+    let available: usize = parts.iter().map(|p| p.len().saturating_sub(40)).sum();
+    let mut out: Option<Vec<u8>> = None;
+    let mut filled = 0;
+    for ext in parts {
+        let total = be_u32(ext.get(32..36)?) as usize;
+        if total > available {
+            return None;
+        }
+        let offset = be_u32(ext.get(36..40)?) as usize;
+        let chunk = &ext[40..];
+        let out = out.get_or_insert_with(|| vec![0; total]);
+        out.get_mut(offset..offset + chunk.len())?.copy_from_slice(chunk);
+        filled += chunk.len();
+    }
+    out.filter(|out| filled == out.len())
+}
+
+fn read_png(data: &[u8]) -> Metadata {
+    let mut meta = Metadata::default();
+    for (name, range) in png_chunks(data).unwrap_or_default() {
+        let body = &data[range.start + 8..range.end - 4];
+        match &name {
+            b"iCCP" => {
+                meta.icc = split_nul(body)
+                    .and_then(|(_, rest)| rest.get(1..))
+                    .and_then(|z| miniz_oxide::inflate::decompress_to_vec_zlib(z).ok());
+            }
+            b"eXIf" => meta.exif = Some(body.to_vec()),
+            b"tEXt" => {
+                if let Some((keyword, text)) = split_nul(body) {
+                    meta.text.push((latin1(keyword), latin1(text)));
+                }
+            }
+            b"zTXt" => {
+                if let Some((keyword, rest)) = split_nul(body)
+                    && let Some(text) = rest.get(1..).and_then(|z| miniz_oxide::inflate::decompress_to_vec_zlib(z).ok()) {
+                    meta.text.push((latin1(keyword), latin1(&text)));
+                }
+            }
+            b"iTXt" => {
+                let Some((keyword, rest)) = split_nul(body) else { continue };
+                let Some(&[compressed, _]) = rest.get(..2) else { continue };
+                let Some((_, rest)) = split_nul(&rest[2..]) else { continue };
+                let Some((_, text)) = split_nul(rest) else { continue };
+                let text = if compressed == 1 {
+                    match miniz_oxide::inflate::decompress_to_vec_zlib(text) {
+                        Ok(t) => t,
+                        Err(_) => continue,
+                    }
+                } else {
+                    text.to_vec()
+                };
+                let keyword = latin1(keyword);
+                if keyword == XMP_KEYWORD {
+                    meta.xmp = Some(text);
+                } else {
+                    meta.text.push((keyword, String::from_utf8_lossy(&text).into_owned()));
+                }
+            }
+            _ => {}
+        }
+    }
+    meta
+}
+
+fn read_webp(data: &[u8]) -> Metadata {
+    let mut meta = Metadata::default();
+    for (name, range) in riff_chunks(data).unwrap_or_default() {
+        let body = data[range.start + 8..range.start + 8 + chunk_len(data, range.start)].to_vec();
+        match &name {
+            b"ICCP" => meta.icc = Some(body),
+            b"EXIF" => meta.exif = Some(body.strip_prefix(EXIF_PREFIX).map(<[u8]>::to_vec).unwrap_or(body)),
+            b"XMP " => meta.xmp = Some(body),
+            _ => {}
+        }
+    }
+    meta
+}
+
+/// TIFF stores ICC and XMP as tags of its first IFD. Of its EXIF-style tags, only artist and
+/// copyright are carried over.
+fn read_tiff(data: &[u8]) -> Metadata {
+    let Some(tiff) = Tiff::new(data) else { return Metadata::default() };
+    let tag = |tag| {
+        let ifd0 = tiff.ifd0()?;
+        tiff.value(tiff.find(ifd0, tag)?).map(|range| data[range].to_vec())
+    };
+    Metadata { icc: tag(TAG_ICC), exif: exif_copyright_only(data), xmp: tag(TAG_XMP), ..Default::default() }
+}
+
+/// Marker segments between SOI and SOS, as ranges covering marker, length and payload.
+fn jpeg_segments(data: &[u8]) -> Option<Vec<Range<usize>>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        if marker == 0xDA || marker == 0xD9 {
+            return Some(segments);
+        }
+        let len = be_u16(data.get(pos + 2..pos + 4)?) as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > data.len() {
+            return None;
+        }
+        segments.push(pos..end);
+        pos = end;
+    }
+}
+
+fn is_metadata_segment(data: &[u8], segment: &Range<usize>) -> bool {
+    let payload = &data[segment.start + 4..segment.end];
+    match data[segment.start + 1] {
+        0xE1 => payload.starts_with(EXIF_PREFIX) || payload.starts_with(XMP_PREFIX) || payload.starts_with(XMP_EXTENSION_PREFIX),
+        0xE2 => payload.starts_with(ICC_PREFIX),
+        0xED => payload.starts_with(IPTC_PREFIX),
+        _ => false,
+    }
+}
+
+/// Writes one marker segment. `what` names the metadata in the error for a payload too large for it.
+fn push_jpeg_segment(out: &mut Vec<u8>, marker: u8, parts: &[&[u8]], what: &str) -> Result<(), OptimizeError> {
+    let len: usize = parts.iter().map(|p| p.len()).sum();
+    if len > JPEG_SEGMENT_MAX {
+        return Err(OptimizeError::Encode(format!(
+            "{} of {} bytes doesn't fit in a JPEG segment ({} at most); choose a --metadata policy that drops it",
+            what, len, JPEG_SEGMENT_MAX
+        )));
+    }
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&(len as u16 + 2).to_be_bytes());
+    for part in parts {
+        out.extend_from_slice(part);
+    }
+    Ok(())
+}
+
+/// Writes `xmp` in one segment or, with an `extended` part or when it doesn't fit, as extended XMP:
+/// a main packet naming the extended part by its MD5, which follows in extension segments.
+fn push_jpeg_xmp(out: &mut Vec<u8>, xmp: &[u8], extended: Option<&[u8]>) -> Result<(), OptimizeError> {
+    let (main, extended) = match extended {
+        Some(extended) => (xmp, extended),
+        None if XMP_PREFIX.len() + xmp.len() <= JPEG_SEGMENT_MAX => return push_jpeg_segment(out, 0xE1, &[XMP_PREFIX, xmp], "XMP"),
+        None => (&[][..], xmp),
+    };
+    let guid = format!("{:X}", md5::compute(extended));
+    let main = match extended_xmp_guid(main) {
+        // The extended part may have changed (GPS stripped), so the main packet gets its new GUID.
+        Some(old) => {
+            let at = old.as_ptr() as usize - main.as_ptr() as usize;
+            [&main[..at], guid.as_bytes(), &main[at + 32..]].concat()
+        }
+        None => format!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?><x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+             <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\"><rdf:Description rdf:about=\"\" \
+             xmlns:xmpNote=\"http://ns.adobe.com/xmp/note/\" {}=\"{}\"/></rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>",
+            HAS_EXTENDED_XMP, guid
+        ).into_bytes(),
+    };
+    push_jpeg_segment(out, 0xE1, &[XMP_PREFIX, &main], "XMP")?;
+    let total = (extended.len() as u32).to_be_bytes();
+    for (i, chunk) in extended.chunks(XMP_EXTENSION_CHUNK).enumerate() {
+        let offset = ((i * XMP_EXTENSION_CHUNK) as u32).to_be_bytes();
+        push_jpeg_segment(out, 0xE1, &[XMP_EXTENSION_PREFIX, guid.as_bytes(), &total, &offset, chunk], "XMP")?;
+    }
+    Ok(())
+}
+
+/// Chunks after the signature, with ranges covering length, type, data and CRC.
+fn png_chunks(data: &[u8]) -> Option<Vec<([u8; 4], Range<usize>)>> {
+    if !data.starts_with(PNG_SIGNATURE) {
+        return None;
+    }
+    let mut chunks = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        let len = be_u32(data.get(pos..pos + 4)?) as usize;
+        let name: [u8; 4] = data.get(pos + 4..pos + 8)?.try_into().ok()?;
+        let end = pos.checked_add(12 + len).filter(|&e| e <= data.len())?;
+        chunks.push((name, pos..end));
+        pos = end;
+        if &name == b"IEND" {
+            break;
+        }
+    }
+    Some(chunks)
+}
+
+fn push_png_chunk(out: &mut Vec<u8>, name: &[u8; 4], parts: &[&[u8]]) {
+    let len: usize = parts.iter().map(|p| p.len()).sum();
+    out.extend_from_slice(&(len as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(name);
+    for part in parts {
+        out.extend_from_slice(part);
+    }
+    let crc = crc32fast::hash(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn is_webp(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP"
+}
+
+/// Chunks of a WebP RIFF container, with ranges covering header, data and padding.
+fn riff_chunks(data: &[u8]) -> Option<Vec<([u8; 4], Range<usize>)>> {
+    if !is_webp(data) {
+        return None;
+    }
+    let mut chunks = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let name: [u8; 4] = data[pos..pos + 4].try_into().ok()?;
+        let len = chunk_len(data, pos);
+        let end = (pos + 8 + len + (len & 1)).min(data.len());
+        if pos + 8 + len > data.len() {
+            return None;
+        }
+        chunks.push((name, pos..end));
+        pos = end;
+    }
+    Some(chunks)
+}
+
+fn chunk_len(data: &[u8], pos: usize) -> usize {
+    u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize
+}
+
+fn push_riff_chunk(out: &mut Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(name);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn riff_file(chunks: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(chunks.len() + 12);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend_from_slice(chunks);
+    out
+}
+
+/// Canvas size from a `VP8 ` or `VP8L` frame.
+fn webp_frame_size(name: &[u8; 4], body: &[u8]) -> Option<(u32, u32)> {
+    match name {
+        b"VP8 " if body.len() >= 10 => {
+            Some(((le_u16(&body[6..8]) & 0x3FFF) as u32, (le_u16(&body[8..10]) & 0x3FFF) as u32))
+        }
+        b"VP8L" if body.len() >= 5 => {
+            let bits = u32::from_le_bytes(body[1..5].try_into().ok()?);
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+        }
+        _ => None,
+    }
+}
+
+/// Minimal view of a TIFF structure, as stored in EXIF.
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..4)? {
+            b"MM\0*" => true,
+            b"II*\0" => false,
+            _ => return None,
+        };
+        Some(Tiff { data, big_endian })
+    }
+
+    fn u16(&self, pos: usize) -> Option<u16> {
+        let b: [u8; 2] = self.data.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    }
+
+    fn u32(&self, pos: usize) -> Option<u32> {
+        let b: [u8; 4] = self.data.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    fn ifd0(&self) -> Option<usize> {
+        self.u32(4).map(|o| o as usize)
+    }
+
+    /// Offsets of the 12-byte entries of the IFD at `ifd`.
+    fn entries(&self, ifd: usize) -> Vec<usize> {
+        let count = self.u16(ifd).unwrap_or(0) as usize;
+        (0..count).map(|i| ifd + 2 + i * 12).filter(|&e| e + 12 <= self.data.len()).collect()
+    }
+
+    fn find(&self, ifd: usize, tag: u16) -> Option<usize> {
+        self.entries(ifd).into_iter().find(|&e| self.u16(e) == Some(tag))
+    }
+
+    /// Byte range of the value of the entry at `entry`.
+    fn value(&self, entry: usize) -> Option<Range<usize>> {
+        let unit = match self.u16(entry + 2)? {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => return None,
+        };
+        let len = unit * self.u32(entry + 4)? as usize;
+        let start = if len <= 4 { entry + 8 } else { self.u32(entry + 8)? as usize };
+        let end = start.checked_add(len).filter(|&e| e <= self.data.len())?;
+        Some(start..end)
+    }
+}
+
+/// EXIF with only the artist and copyright of `exif`, or `None` when it has neither.
+fn exif_copyright_only(exif: &[u8]) -> Option<Vec<u8>> {
+    let tiff = Tiff::new(exif)?;
+    let ifd0 = tiff.ifd0()?;
+    let entries: Vec<(u16, &[u8])> = [TAG_ARTIST, TAG_COPYRIGHT].into_iter()
+        .filter_map(|tag| {
+            let value = tiff.value(tiff.find(ifd0, tag)?)?;
+            let value = &exif[value];
+            Some((tag, value.strip_suffix(b"\0").unwrap_or(value)))
+        })
+        .collect();
+    (!entries.is_empty()).then(|| build_exif(&entries))
+}
+
+/// Big-endian EXIF with one IFD of ASCII entries, which must be sorted by tag.
+fn build_exif(entries: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut out = b"MM\0*\0\0\0\x08".to_vec();
+    out.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+    let mut data_offset = 8 + 2 + entries.len() * 12 + 4;
+    let mut data = Vec::new();
+    for (tag, value) in entries {
+        let mut value = value.to_vec();
+        value.push(0);
+        out.extend_from_slice(&tag.to_be_bytes());
+        out.extend_from_slice(&2u16.to_be_bytes());
+        out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        if value.len() <= 4 {
+            value.resize(4, 0);
+            out.extend_from_slice(&value);
+        } else {
+            out.extend_from_slice(&(data_offset as u32).to_be_bytes());
+            data_offset += value.len();
+            data.extend_from_slice(&value);
+        }
+    }
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&data);
+    out
+}
+
+/// Empties the GPS IFD of `exif` in place: its values are zeroed and its entry count set to 0.
+fn exif_strip_gps(exif: &mut [u8]) {
+    let (gps, ranges) = {
+        let Some(tiff) = Tiff::new(exif) else { return };
+        let Some(pointer) = tiff.ifd0().and_then(|ifd0| tiff.find(ifd0, TAG_GPS_IFD)) else { return };
+        let Some(gps) = tiff.u32(pointer + 8).map(|o| o as usize) else { return };
+        let entries = tiff.entries(gps);
+        let mut ranges: Vec<Range<usize>> = entries.iter().filter_map(|&e| tiff.value(e)).collect();
+        if let Some(last) = entries.last() {
+            ranges.push(gps..last + 12);
+        }
+        (gps, ranges)
+    };
+    for range in ranges {
+        exif[range].fill(0);
+    }
+    if let Some(count) = exif.get_mut(gps..gps + 2) {
+        count.fill(0);
+    }
+}
+
+/// `xmp` without `exif:GPS*` properties, in attribute or element form. Works on the bytes, so
+/// packets in any ASCII-compatible encoding come out unchanged apart from the removed properties.
+fn xmp_strip_gps(xmp: &[u8]) -> Vec<u8> {
+    let mut data = xmp.to_vec();
+    let mut from = 0;
+    while let Some(found) = find(&data[from..], b"exif:GPS") {
+        let start = from + found;
+        let name_end = data[start..].iter().position(|&b| !(b.is_ascii_alphanumeric() || b == b':')).map_or(data.len(), |i| start + i);
+        let name = data[start..name_end].to_vec();
+        if data[..start].ends_with(b"<") {
+            // `<exif:GPSLatitude>...</exif:GPSLatitude>` or `<exif:GPSLatitude/>`
+            let open = start - 1;
+            let close = [b"</", name.as_slice(), b">"].concat();
+            let tag_end = data[start..].iter().position(|&b| b == b'>').map(|i| start + i);
+            let end = match tag_end {
+                Some(e) if data[..e].ends_with(b"/") => Some(e + 1),
+                Some(e) => find(&data[e..], &close).map(|i| e + i + close.len()),
+                None => None,
+            };
+            match end {
+                Some(end) => drop(data.drain(open..end)),
+                None => break,
+            }
+            from = open;
+        } else if data[name_end..].starts_with(b"=\"") || data[name_end..].starts_with(b"='") {
+            let quote = data[name_end + 1];
+            let Some(close) = data[name_end + 2..].iter().position(|&b| b == quote) else { break };
+            let trimmed = data[..start].iter().rposition(|b| !b.is_ascii_whitespace()).map_or(0, |i| i + 1);
+            data.drain(trimmed..name_end + 2 + close + 1);
+            from = trimmed;
+        } else {
+            from = name_end;
+        }
+    }
+    data
+}
+
+fn split_nul(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let i = data.iter().position(|&b| b == 0)?;
+    Some((&data[..i], &data[i + 1..]))
+}
+
+fn latin1(data: &[u8]) -> String {
+    data.iter().map(|&b| b as char).collect()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn be_u16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+fn be_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn le_u16(b: &[u8]) -> u16 {
+    u16::from_le_bytes([b[0], b[1]])
+}
+
+fn u24(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], 0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_ops::{encode_as, encode_avif, encode_jpg_image, encode_webp};
+    use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+
+    const POLICIES: [MetadataPolicy; 5] = [
+        MetadataPolicy::StripAll,
+        MetadataPolicy::KeepAll,
+        MetadataPolicy::KeepIcc,
+        MetadataPolicy::KeepCopyright,
+        MetadataPolicy::StripGps,
+    ];
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| Rgb([x as u8 * 16, y as u8 * 16, 128])))
+    }
+
+    /// Big-endian EXIF with an artist, a copyright and a GPS IFD holding a latitude.
+    fn exif_with_gps() -> Vec<u8> {
+        let mut exif = b"MM\0*\0\0\0\x08".to_vec();
+        let entry = |exif: &mut Vec<u8>, tag: u16, kind: u16, count: u32, value: [u8; 4]| {
+            exif.extend_from_slice(&tag.to_be_bytes());
+            exif.extend_from_slice(&kind.to_be_bytes());
+            exif.extend_from_slice(&count.to_be_bytes());
+            exif.extend_from_slice(&value);
+        };
+        // IFD0 at 8, its data at 50, the GPS IFD at 60 and the GPS data at 90.
+        exif.extend_from_slice(&3u16.to_be_bytes());
+        entry(&mut exif, TAG_ARTIST, 2, 4, *b"Ann\0");
+        entry(&mut exif, TAG_COPYRIGHT, 2, 10, 50u32.to_be_bytes());
+        entry(&mut exif, TAG_GPS_IFD, 4, 1, 60u32.to_be_bytes());
+        exif.extend_from_slice(&[0; 4]);
+        exif.extend_from_slice(b"ACME Corp\0");
+        exif.extend_from_slice(&2u16.to_be_bytes());
+        entry(&mut exif, 1, 2, 2, *b"N\0\0\0");
+        entry(&mut exif, 2, 5, 3, 90u32.to_be_bytes());
+        exif.extend_from_slice(&[0; 4]);
+        for value in [48u32, 1, 51, 1, 1234, 100] {
+            exif.extend_from_slice(&value.to_be_bytes());
+        }
+        exif
+    }
+
+    fn xmp_with_gps() -> Vec<u8> {
+        b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF><rdf:Description dc:creator=\"Ann\" exif:GPSLatitude=\"48,51.12N\">\
+          <exif:GPSLongitude>2,21.07E</exif:GPSLongitude></rdf:Description></rdf:RDF></x:xmpmeta>".to_vec()
+    }
+
+    /// Stands in for an ICC profile; nothing here parses it.
+    fn icc(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn gps_entries(exif: &[u8]) -> Option<u16> {
+        let tiff = Tiff::new(exif)?;
+        let pointer = tiff.find(tiff.ifd0()?, TAG_GPS_IFD)?;
+        tiff.u16(tiff.u32(pointer + 8)? as usize)
+    }
+
+    fn assert_no_gps(meta: &Metadata) {
+        assert_eq!(meta.exif.as_deref().and_then(gps_entries), Some(0));
+        // The latitude values are gone too, not just unreferenced.
+        assert!(find(meta.exif.as_deref().unwrap(), &1234u32.to_be_bytes()).is_none());
+        assert!(find(meta.xmp.as_deref().unwrap(), b"GPS").is_none());
+        assert!(find(meta.xmp.as_deref().unwrap(), b"dc:creator").is_some());
+    }
+
+    /// JPEG marker segments of `data` with `marker` whose payload starts with `prefix`.
+    fn jpeg_segments_with(data: &[u8], marker: u8, prefix: &[u8]) -> usize {
+        jpeg_segments(data).unwrap().iter().filter(|s| data[s.start + 1] == marker && data[s.start + 4..].starts_with(prefix)).count()
+    }
+
+    #[test]
+    fn xmp_gps_is_stripped_from_the_bytes() {
+        // A Latin-1 "é" isn't UTF-8 and has to survive as the single byte it is.
+        let xmp = b"<rdf:Description dc:title='Caf\xe9' exif:GPSAltitude='35/1'><exif:GPSTrack/></rdf:Description>";
+        assert_eq!(xmp_strip_gps(xmp), b"<rdf:Description dc:title='Caf\xe9'></rdf:Description>");
+        let stripped = xmp_strip_gps(&xmp_with_gps());
+        assert!(find(&stripped, b"GPS").is_none());
+        assert!(find(&stripped, b"dc:creator=\"Ann\">").is_some());
+    }
+
+    #[test]
+    fn policies_combine() {
+        assert_eq!(MetadataPolicy::keep(&[MetadataPolicy::StripAll]), Keep { icc: false, level: KeepLevel::None });
+        assert_eq!(MetadataPolicy::keep(&[MetadataPolicy::KeepIcc, MetadataPolicy::KeepCopyright]), Keep { icc: true, level: KeepLevel::Copyright });
+        assert_eq!(MetadataPolicy::keep(&[MetadataPolicy::KeepAll, MetadataPolicy::StripGps]), Keep { icc: true, level: KeepLevel::NoGps });
+    }
+
+    #[test]
+    fn jpeg_round_trips_every_policy() {
+        let source = Metadata {
+            icc: Some(icc(1000)),
+            exif: Some(exif_with_gps()),
+            xmp: Some(xmp_with_gps()),
+            iptc: Some([IPTC_PREFIX, b"8BIM\x04\x04\0\0\0\0\0\0"].concat()),
+            ..Default::default()
+        };
+        let jpeg = encode_jpg_image(&image(), 80).unwrap();
+        for policy in POLICIES {
+            let kept = source.filter(MetadataPolicy::keep(&[policy]));
+            let out = kept.apply_jpeg(&jpeg).unwrap();
+            image::load_from_memory_with_format(&out, ImageFormat::Jpeg).unwrap();
+            assert_eq!(Metadata::read(&out), kept, "{}", policy);
+            match policy {
+                MetadataPolicy::StripAll => assert!(Metadata::read(&out).is_empty()),
+                MetadataPolicy::KeepCopyright => {
+                    let exif = Metadata::read(&out).exif.unwrap();
+                    assert_eq!(exif_copyright_only(&exif).as_deref(), Some(&exif[..]));
+                    assert!(gps_entries(&exif).is_none());
+                }
+                MetadataPolicy::StripGps => assert_no_gps(&Metadata::read(&out)),
+                MetadataPolicy::KeepAll => assert_eq!(Metadata::read(&out), source),
+                MetadataPolicy::KeepIcc => assert_eq!(Metadata::read(&out), Metadata { icc: source.icc.clone(), ..Default::default() }),
+            }
+        }
+    }
+
+    #[test]
+    fn jpeg_replaces_existing_metadata() {
+        let jpeg = encode_jpg_image(&image(), 80).unwrap();
+        let tagged = Metadata { exif: Some(exif_with_gps()), xmp: Some(xmp_with_gps()), ..Default::default() }.apply_jpeg(&jpeg).unwrap();
+        let out = Metadata::default().apply_jpeg(&tagged).unwrap();
+        assert_eq!(out, jpeg);
+    }
+
+    #[test]
+    fn large_jpeg_icc_is_split_across_app2_segments() {
+        let meta = Metadata { icc: Some(icc(150_000)), ..Default::default() };
+        let out = meta.apply_jpeg(&encode_jpg_image(&image(), 80).unwrap()).unwrap();
+        assert_eq!(jpeg_segments_with(&out, 0xE2, ICC_PREFIX), 3);
+        image::load_from_memory_with_format(&out, ImageFormat::Jpeg).unwrap();
+        assert_eq!(Metadata::read(&out), meta);
+    }
+
+    #[test]
+    fn large_jpeg_xmp_becomes_extended_xmp() {
+        let mut xmp = xmp_with_gps();
+        xmp.extend(b"<!-- padding -->".repeat(10_000));
+        let jpeg = encode_jpg_image(&image(), 80).unwrap();
+        let out = Metadata { xmp: Some(xmp.clone()), ..Default::default() }.apply_jpeg(&jpeg).unwrap();
+        assert_eq!(jpeg_segments_with(&out, 0xE1, XMP_PREFIX), 1);
+        assert_eq!(jpeg_segments_with(&out, 0xE1, XMP_EXTENSION_PREFIX), 3);
+        image::load_from_memory_with_format(&out, ImageFormat::Jpeg).unwrap();
+
+        let read = Metadata::read(&out);
+        assert_eq!(read.xmp_extended.as_deref(), Some(&xmp[..]));
+        let guid = format!("{:X}", md5::compute(&xmp));
+        assert_eq!(read.xmp.as_deref().and_then(extended_xmp_guid), Some(guid.as_bytes()));
+        // Written back unchanged, it stays the same.
+        assert_eq!(read.apply_jpeg(&jpeg).unwrap(), out);
+
+        // Stripping GPS changes the extended part, and the main packet follows its new digest.
+        let stripped = read.filter(MetadataPolicy::keep(&[MetadataPolicy::StripGps]));
+        let read = Metadata::read(&stripped.apply_jpeg(&jpeg).unwrap());
+        let extended = read.xmp_extended.unwrap();
+        assert!(find(&extended, b"GPS").is_none());
+        let guid = format!("{:X}", md5::compute(&extended));
+        assert_eq!(read.xmp.as_deref().and_then(extended_xmp_guid), Some(guid.as_bytes()));
+    }
+
+    #[test]
+    fn oversized_jpeg_exif_is_an_error() {
+        let meta = Metadata { exif: Some([&exif_with_gps()[..], &[0; JPEG_SEGMENT_MAX]].concat()), ..Default::default() };
+        let error = meta.apply_jpeg(&encode_jpg_image(&image(), 80).unwrap()).unwrap_err();
+        assert!(error.to_string().contains("EXIF"));
+    }
+
+    #[test]
+    fn png_round_trips_every_policy() {
+        let source = Metadata {
+            icc: Some(icc(1000)),
+            exif: Some(exif_with_gps()),
+            xmp: Some(xmp_with_gps()),
+            text: vec![("Copyright".into(), "ACME Corp".into()), ("Comment".into(), "draft".into())],
+            ..Default::default()
+        };
+        let png = encode_as(&image(), ImageFormat::Png).unwrap();
+        for policy in POLICIES {
+            let kept = source.filter(MetadataPolicy::keep(&[policy]));
+            let out = kept.apply_png(&png).unwrap();
+            image::load_from_memory_with_format(&out, ImageFormat::Png).unwrap();
+            assert_eq!(Metadata::read(&out), kept, "{}", policy);
+            if policy == MetadataPolicy::StripGps {
+                assert_no_gps(&Metadata::read(&out));
+            }
+        }
+        let copyright = source.filter(MetadataPolicy::keep(&[MetadataPolicy::KeepCopyright]));
+        assert_eq!(copyright.text, [("Copyright".to_string(), "ACME Corp".to_string())]);
+    }
+
+    #[test]
+    fn webp_round_trips_every_policy() {
+        let source = Metadata { icc: Some(icc(1001)), exif: Some(exif_with_gps()), xmp: Some(xmp_with_gps()), ..Default::default() };
+        let webp = encode_webp(&image(), 75.0).unwrap();
+        for policy in POLICIES {
+            let kept = source.filter(MetadataPolicy::keep(&[policy]));
+            let out = kept.apply_webp(&webp).unwrap();
+            image::load_from_memory_with_format(&out, ImageFormat::WebP).unwrap();
+            assert_eq!(Metadata::read(&out), kept, "{}", policy);
+            let first = &riff_chunks(&out).unwrap()[0].0;
+            assert_eq!(first, if kept.is_empty() { b"VP8 " } else { b"VP8X" }, "{}", policy);
+            if policy == MetadataPolicy::StripGps {
+                assert_no_gps(&Metadata::read(&out));
+            }
+        }
+    }
+
+    #[test]
+    fn animated_webp_keeps_its_animation_flag() {
+        let config = webp::WebPConfig::new().unwrap();
+        let frames: Vec<Vec<u8>> = (0..3u8).map(|i| [i * 80, 0, 255 - i * 80, 255].repeat(16 * 16)).collect();
+        let mut encoder = webp::AnimEncoder::new(16, 16, &config);
+        for (i, frame) in frames.iter().enumerate() {
+            encoder.add_frame(webp::AnimFrame::from_rgba(frame, 16, 16, i as i32 * 100));
+        }
+        let animation = encoder.encode().to_vec();
+
+        for meta in [Metadata::default(), Metadata { exif: Some(exif_with_gps()), ..Default::default() }] {
+            let out = meta.apply_webp(&animation).unwrap();
+            let chunks = riff_chunks(&out).unwrap();
+            assert_eq!(&chunks[0].0, b"VP8X");
+            assert_ne!(out[chunks[0].1.start + 8] & 0x02, 0);
+            assert_eq!(webp::AnimDecoder::new(&out).decode().unwrap().len(), 3);
+            assert_eq!(Metadata::read(&out), meta);
+        }
+    }
+
+    /// `(offset, length)` of every extent in the `iloc` box of an AVIF.
+    fn iloc_extents(file: &[u8]) -> Vec<(u64, u64)> {
+        let meta = isobmff_children(file, 0..file.len()).unwrap().into_iter().find(|b| &b.0 == b"meta").unwrap().1;
+        let iloc = isobmff_children(file, meta.start + 12..meta.end).unwrap().into_iter().find(|b| &b.0 == b"iloc").unwrap().1;
+        let version = file[iloc.start + 8];
+        let (offset_size, length_size) = ((file[iloc.start + 12] >> 4) as usize, (file[iloc.start + 12] & 0xF) as usize);
+        let (base_size, index_size) = ((file[iloc.start + 13] >> 4) as usize, if version >= 1 { (file[iloc.start + 13] & 0xF) as usize } else { 0 });
+        let id_size = if version < 2 { 2 } else { 4 };
+        let mut pos = iloc.start + 14;
+        let count = read_be(file, pos, id_size).unwrap();
+        pos += id_size;
+        let mut extents = Vec::new();
+        for _ in 0..count {
+            pos += id_size + if version >= 1 { 2 } else { 0 } + 2;
+            let base = read_be(file, pos, base_size).unwrap();
+            pos += base_size;
+            let n = read_be(file, pos, 2).unwrap();
+            pos += 2;
+            for _ in 0..n {
+                pos += index_size;
+                let offset = read_be(file, pos, offset_size).unwrap();
+                let length = read_be(file, pos + offset_size, length_size).unwrap();
+                extents.push((base + offset, length));
+                pos += offset_size + length_size;
+            }
+        }
+        extents
+    }
+
+    fn item_data(file: &[u8]) -> Vec<&[u8]> {
+        iloc_extents(file).into_iter().map(|(offset, length)| &file[offset as usize..(offset + length) as usize]).collect()
+    }
+
+    #[test]
+    fn avif_gets_exif_item_and_icc_with_patched_offsets() {
+        let exif = exif_with_gps();
+        let plain = encode_avif(&image(), 60.0, &Metadata::default()).unwrap();
+        let with_exif = encode_avif(&image(), 60.0, &Metadata { exif: Some(exif.clone()), ..Default::default() }).unwrap();
+        let icc = icc(3000);
+        let full = encode_avif(&image(), 60.0, &Metadata { exif: Some(exif.clone()), icc: Some(icc.clone()), ..Default::default() }).unwrap();
+
+        // The HEIF Exif item: a zero TIFF header offset, then the EXIF.
+        let exif_item = [&[0, 0, 0, 0][..], &exif].concat();
+        assert!(item_data(&with_exif).contains(&&exif_item[..]));
+        assert!(!item_data(&plain).contains(&&exif_item[..]));
+
+        // Adding the profile moves every item; their offsets must still point at the same bytes.
+        assert_eq!(item_data(&full), item_data(&with_exif));
+        let colr = find(&full, b"colrprof").unwrap();
+        assert_eq!(be_u32(&full[colr - 4..]) as usize, icc.len() + 12);
+        assert_eq!(&full[colr + 8..colr + 8 + icc.len()], &icc[..]);
+        // All box sizes up to the file end still add up.
+        assert_eq!(isobmff_children(&full, 0..full.len()).unwrap().last().unwrap().1.end, full.len());
+    }
+}
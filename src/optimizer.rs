@@ -0,0 +1,1385 @@
+use image::{DynamicImage, Frame, ImageFormat};
+use image::codecs::gif::Repeat;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+use crate::backup::{BACKUP_MARKER, Backup, is_backup_path};
+use crate::cache::{CACHE_FILE_NAME, Cache, default_cache_path, hash_file};
+use crate::config::{Config, ConfigResolver, DirConfig};
+use crate::error::OptimizeError;
+use crate::filters::ScanFilter;
+use crate::formats::{FormatPolicy, ImageKind};
+use crate::fs_utils::{NonImages, copy_dir_recursive_filtered, is_temp_file, mirror_file, write_atomic};
+use crate::image_ops::{
+    ColorMode, PngOptions, PngStrip, ResizeFilter, apply_orientation, convert_to_srgb, fit_dimensions, resize, decode_gif_frames, encode_as, encode_gif, gif_repeat, encode_jpg_image, encode_png_image, encode_webp, encode_webp_lossless, encode_avif,
+    WEBP_QUALITY, AVIF_QUALITY, AVIF_SPEED, AVIF_ALPHA_QUALITY, GIF_SPEED,
+};
+use crate::metadata::{Metadata, MetadataPolicy};
+use crate::quality::{DEFAULT_MIN_QUALITY, QualityTarget, Tuning, tune};
+use crate::results::{Dimensions, FileResult, NameCollision, OutputKind, OutputResult, Resized, RunSummary, Tuned};
+
+/// Settings that may differ per file, through `images-optimizer.toml` files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSettings {
+    pub jpg_quality: u8,
+    pub png_min: u8,
+    pub png_max: u8,
+    /// libimagequant speed, 1 (slowest, best) to 10.
+    pub png_speed: u8,
+    /// oxipng optimization level, 0 to 6.
+    pub png_level: u8,
+    pub png_strip: PngStrip,
+    pub zopfli: bool,
+    /// What each input format's optimized original is. Formats not listed are recompressed as they are.
+    pub convert: BTreeMap<ImageKind, FormatPolicy>,
+    /// Give images whose extension doesn't match their content the right one.
+    pub fix_extensions: bool,
+    /// Convert ICC-profiled sources to sRGB, or embed their profile.
+    pub color: ColorMode,
+    /// Which source metadata outputs keep. Policies combine.
+    pub metadata: Vec<MetadataPolicy>,
+    /// Search JPEG/WebP quality per image for this perceptual similarity instead of using fixed values.
+    pub quality_target: Option<QualityTarget>,
+    /// Byte limit for every JPEG, WebP and AVIF output. Quality is searched to stay under it.
+    pub max_bytes: Option<u64>,
+    /// Lowest quality the searches may go down to.
+    pub min_quality: u8,
+    /// With `max_bytes`, allow downscaling down to this width when even `min_quality` is too large.
+    pub min_width: Option<u32>,
+    pub webp: bool,
+    pub avif: bool,
+    /// Appended to safe-mode copies: `photo__optimized.jpg`, `assets__optimized/`.
+    pub output_suffix: String,
+    /// Larger images are downscaled to fit, keeping their aspect ratio. Never upscales.
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_pixels: Option<u64>,
+    pub resize_filter: ResizeFilter,
+    /// Widths of responsive variants to generate in every enabled format.
+    pub widths: Vec<u32>,
+    /// File name of a variant. `{name}`, `{width}` and `{ext}` are substituted.
+    pub variant_template: String,
+    /// Stem of the WebP/AVIF, variant and converted files of same-stem sources that would
+    /// otherwise write the same file. `{name}` and `{source_ext}` are substituted.
+    pub collision_name: String,
+}
+
+pub const DEFAULT_VARIANT_TEMPLATE: &str = "{name}-{width}w.{ext}";
+pub const DEFAULT_COLLISION_NAME: &str = "{name}.{source_ext}";
+
+impl Default for FileSettings {
+    fn default() -> Self {
+        Self {
+            jpg_quality: 80,
+            png_min: 65,
+            png_max: 80,
+            png_speed: 3,
+            png_level: 4,
+            png_strip: PngStrip::default(),
+            zopfli: false,
+            convert: BTreeMap::new(),
+            fix_extensions: false,
+            color: ColorMode::default(),
+            metadata: vec![MetadataPolicy::default()],
+            quality_target: None,
+            max_bytes: None,
+            min_quality: DEFAULT_MIN_QUALITY,
+            min_width: None,
+            webp: false,
+            avif: false,
+            output_suffix: "__optimized".to_string(),
+            max_width: None,
+            max_height: None,
+            max_pixels: None,
+            resize_filter: ResizeFilter::default(),
+            widths: Vec::new(),
+            variant_template: DEFAULT_VARIANT_TEMPLATE.to_string(),
+            collision_name: DEFAULT_COLLISION_NAME.to_string(),
+        }
+    }
+}
+
+impl FileSettings {
+    /// Fingerprint of every setting that affects the outputs of a `kind` image.
+    pub fn cache_settings(&self, kind: ImageKind) -> String {
+        let mut key = format!("v{};", env!("CARGO_PKG_VERSION"));
+        let format = self.original_format(kind);
+        if format != kind {
+            key.push_str(&format!("{}>", kind));
+        }
+        match format {
+            ImageKind::Jpeg => key.push_str(&format!("jpg:q{}", self.jpg_quality)),
+            ImageKind::Png => key.push_str(&format!("png:q{}-{}/s{};oxi:o{}/{}/z{}", self.png_min, self.png_max, self.png_speed, self.png_level, self.png_strip, self.zopfli as u8)),
+            ImageKind::WebP => key.push_str(&format!("webp:q{}", WEBP_QUALITY)),
+            ImageKind::Gif => key.push_str(&format!("gif:s{}", GIF_SPEED)),
+            other => key.push_str(&other.to_string()),
+        }
+        let metadata: Vec<String> = self.metadata.iter().map(|m| m.to_string()).collect();
+        key.push_str(&format!(";meta:{};color:{}", metadata.join(","), self.color));
+        if self.has_size_limits() {
+            let limit = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+            key.push_str(&format!(";max:{}x{}/{}/{}",
+                limit(self.max_width.map(|v| v.to_string())),
+                limit(self.max_height.map(|v| v.to_string())),
+                limit(self.max_pixels.map(|v| v.to_string())),
+                self.resize_filter
+            ));
+        }
+        if !self.widths.is_empty() {
+            let widths: Vec<String> = self.widths.iter().map(|w| w.to_string()).collect();
+            key.push_str(&format!(";variants:{}/{}", widths.join(","), self.variant_template));
+        }
+        if let Some(target) = self.quality_target {
+            key.push_str(&format!(";target:{}", target));
+        }
+        if let Some(limit) = self.max_bytes {
+            key.push_str(&format!(";max-bytes:{}/w{}", limit, self.min_width.unwrap_or(0)));
+        }
+        if self.tuning().is_active() {
+            key.push_str(&format!("/minq{}", self.min_quality));
+        }
+        if self.webp {
+            key.push_str(&format!(";webp:q{}", WEBP_QUALITY));
+        }
+        if self.avif {
+            key.push_str(&format!(";avif:q{}/s{}/a{}", AVIF_QUALITY, AVIF_SPEED, AVIF_ALPHA_QUALITY));
+        }
+        key
+    }
+
+    /// True when `file_name` looks like one of our own responsive variants, so that re-runs
+    /// don't pick variants up as new sources.
+    pub fn is_variant_name(&self, file_name: &OsStr) -> bool {
+        let file_name = file_name.to_string_lossy();
+        let ext = Path::new(file_name.as_ref()).extension().unwrap_or_default().to_string_lossy();
+        self.widths.iter().any(|w| {
+            let pattern = self.variant_template.replace("{width}", &w.to_string()).replace("{ext}", &ext);
+            match pattern.split_once("{name}") {
+                Some((pre, post)) => file_name.len() > pre.len() + post.len()
+                    && file_name.starts_with(pre)
+                    && file_name.ends_with(post),
+                None => file_name == pattern,
+            }
+        })
+    }
+
+    pub fn tuning(&self) -> Tuning {
+        Tuning {
+            target: self.quality_target,
+            max_bytes: self.max_bytes,
+            min_quality: self.min_quality,
+            min_width: self.min_width,
+            filter: self.resize_filter,
+        }
+    }
+
+    pub fn png_options(&self) -> PngOptions {
+        PngOptions {
+            min: self.png_min,
+            max: self.png_max,
+            speed: self.png_speed,
+            level: self.png_level,
+            strip: self.png_strip,
+            zopfli: self.zopfli,
+        }
+    }
+
+    /// Format of the optimized original of a `kind` input.
+    pub fn original_format(&self, kind: ImageKind) -> ImageKind {
+        self.convert.get(&kind).map_or(kind, |policy| policy.target(kind))
+    }
+
+    /// Where the optimized original of the `kind` image at `path` goes: `path` itself, unless it is
+    /// converted or its extension is fixed.
+    pub fn original_path(&self, kind: ImageKind, path: &Path) -> PathBuf {
+        let format = self.original_format(kind);
+        if format != kind || (self.fix_extensions && ImageKind::from_path(path) != Some(kind)) {
+            path.with_extension(format.extension())
+        } else {
+            path.to_path_buf()
+        }
+    }
+
+    /// The original plus every enabled extra format. An original in `format` WebP already is the WebP output.
+    pub fn output_kinds(&self, format: ImageKind) -> Vec<OutputKind> {
+        let mut kinds = vec![OutputKind::Original];
+        if self.webp && format != ImageKind::WebP { kinds.push(OutputKind::WebP); }
+        if self.avif { kinds.push(OutputKind::Avif); }
+        kinds
+    }
+
+    pub fn has_size_limits(&self) -> bool {
+        self.max_width.is_some() || self.max_height.is_some() || self.max_pixels.is_some()
+    }
+}
+
+/// Settings for a whole run.
+#[derive(Debug, Clone, Default)]
+pub struct OptimizeOptions {
+    /// Used wherever no config file says otherwise.
+    pub settings: FileSettings,
+    /// Explicitly requested values (e.g. CLI flags). They win over config files.
+    pub overrides: Config,
+    /// Read `images-optimizer.toml` files from each target upward and in its subdirectories.
+    pub config_files: bool,
+    /// Overwrite originals in place instead of working on `__optimized` copies.
+    pub replace: bool,
+    /// Skip images the content-hash cache records as already optimized with the same settings.
+    pub incremental: bool,
+    /// Cache location. Defaults to a hidden file next to the first target directory.
+    pub cache_file: Option<PathBuf>,
+    /// Encode everything in memory and report projected sizes without writing or copying anything.
+    pub dry_run: bool,
+    /// Back up every file before it is overwritten or removed, with a journal for `backup::undo`.
+    pub backup: Option<PathBuf>,
+    /// Write optimized images straight from their sources into a mirrored tree here, instead of
+    /// copying everything to `__optimized` first. Ignored with `replace`.
+    pub out_dir: Option<PathBuf>,
+    /// What `out_dir` runs do with files that aren't optimized.
+    pub non_images: NonImages,
+    /// Read `.gitignore` (inside git repositories) and `.imageoptimizerignore` files.
+    pub ignore_files: bool,
+    /// Only optimize files matching one of these globs, relative to each input directory. All when empty.
+    pub include: Vec<String>,
+    /// Skip files and directories matching any of these globs, relative to each input directory.
+    pub exclude: Vec<String>,
+    /// Also scan files and directories whose name starts with a dot.
+    pub hidden: bool,
+    /// How deep to scan input directories. 1 only looks at files directly inside.
+    pub max_depth: Option<usize>,
+    /// Follow symbolic links. Without it they are skipped.
+    pub follow_links: bool,
+}
+
+/// Progress notifications emitted while discovering and processing files.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// A single directory was given; `target` is where optimization happens.
+    DirectoryMode { replace: bool, target: &'a Path },
+    CopyFinished { duration: Duration },
+    Scanning { dir: &'a Path },
+    /// Several paths (or a single file) were given.
+    ListMode,
+    ListDirectory { source: &'a Path, target: &'a Path },
+    NotFound { path: &'a Path },
+    Unsupported { path: &'a Path },
+    CopyFailed { path: &'a Path, error: &'a io::Error },
+    /// Several files would have written `collision.path`; they were given distinct names.
+    NameCollision { collision: &'a NameCollision },
+    /// `path` lies where another image writes its WebP version, so it is taken for the output of an
+    /// earlier run and left out.
+    EarlierOutput { path: &'a Path },
+    CacheSaveFailed { path: &'a Path, error: &'a io::Error },
+    /// Watch mode is active on `dir`.
+    Watching { dir: &'a Path },
+    WatchError { error: &'a io::Error },
+    FileStarted { path: &'a Path },
+    FileFinished { result: &'a FileResult },
+}
+
+/// A file to optimize. `naming_path` is the base used for WebP/AVIF siblings.
+#[derive(Debug, Clone)]
+pub struct PlanEntry {
+    /// Where the image is read from. Same as `path` unless this is a dry run in safe mode,
+    /// an `out_dir` run, or the image is renamed.
+    pub source: PathBuf,
+    /// The file named by the input. Same as `source` unless that is a safe-mode copy.
+    pub input: PathBuf,
+    /// Where the optimized original goes.
+    pub path: PathBuf,
+    pub naming_path: PathBuf,
+    /// What the file is, by its content.
+    pub kind: ImageKind,
+    /// Format of the optimized original, from the `convert` policy.
+    pub format: ImageKind,
+    /// The file's extension doesn't match `kind`.
+    pub extension_mismatch: bool,
+    /// The optimized original gets a new extension (converted or fixed) and replaces the source.
+    pub renamed: bool,
+    pub settings: Arc<FileSettings>,
+    /// Content hash taken during discovery, when incremental mode is on.
+    pub hash: Option<String>,
+    /// Another source would write the same files: generated names (and a converted original) use
+    /// the `collision_name` stem.
+    pub disambiguated: bool,
+}
+
+impl PlanEntry {
+    pub(crate) fn new(kind: ImageKind, path: PathBuf, naming_path: PathBuf, settings: Arc<FileSettings>) -> Self {
+        let format = settings.original_format(kind);
+        let extension_mismatch = ImageKind::from_path(&path) != Some(kind);
+        let target = settings.original_path(kind, &path);
+        let renamed = target != path;
+        Self { source: path.clone(), input: path, path: target, naming_path, kind, format, extension_mismatch, renamed, settings, hash: None, disambiguated: false }
+    }
+
+    pub fn is_converted(&self) -> bool {
+        self.kind != self.format
+    }
+
+    pub fn output_kinds(&self) -> Vec<OutputKind> {
+        self.settings.output_kinds(self.format)
+    }
+
+    /// Reads the image from `source`. A source elsewhere than the output is never replaced.
+    pub(crate) fn with_source(mut self, source: PathBuf) -> Self {
+        self.renamed &= source == self.source;
+        self.input = source.clone();
+        self.source = source;
+        self
+    }
+
+    /// Records `input` as the file this entry's safe-mode copy was made from.
+    pub(crate) fn with_input(mut self, input: PathBuf) -> Self {
+        self.input = input;
+        self
+    }
+
+    pub fn output_path(&self, kind: OutputKind) -> PathBuf {
+        match kind {
+            OutputKind::Original => self.path.clone(),
+            OutputKind::WebP => self.naming_path.with_file_name(format!("{}.webp", self.stem(&self.naming_path))),
+            OutputKind::Avif => self.naming_path.with_file_name(format!("{}.avif", self.stem(&self.naming_path))),
+        }
+    }
+
+    /// Stem of the files generated from `path`'s name, through `collision_name` once disambiguated.
+    fn stem(&self, path: &Path) -> String {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        if !self.disambiguated {
+            return stem.into_owned();
+        }
+        let source_ext = match self.source.extension() {
+            Some(ext) => ext.to_string_lossy(),
+            None => self.kind.extension().into(),
+        };
+        self.settings.collision_name.replace("{name}", &stem).replace("{source_ext}", &source_ext)
+    }
+
+    /// The optimized original has the source's name, so it can't be renamed to avoid a clash.
+    fn keeps_source_name(&self) -> bool {
+        self.path.extension() == self.source.extension()
+    }
+
+    /// Switches to the `collision_name` stem for every file whose name this entry chooses.
+    fn disambiguate(&mut self) {
+        if self.disambiguated {
+            return;
+        }
+        self.disambiguated = true;
+        if !self.keeps_source_name() {
+            let ext = self.path.extension().unwrap_or_default().to_string_lossy().into_owned();
+            self.path = self.path.with_file_name(format!("{}.{}", self.stem(&self.path), ext));
+        }
+    }
+
+    /// Every file this entry reads or may write: the source (and, for a lone file in safe mode,
+    /// the input next to which its siblings go), then each enabled output at full size and at every
+    /// configured width. Inputs are `None`.
+    fn claims(&self) -> Vec<Option<(OutputKind, Option<u32>)>> {
+        let mut claims = vec![None];
+        for kind in self.output_kinds() {
+            claims.push(Some((kind, None)));
+            claims.extend(self.settings.widths.iter().map(|&w| Some((kind, Some(w)))));
+        }
+        claims
+    }
+
+    fn claim_paths(&self, claim: Option<(OutputKind, Option<u32>)>) -> Vec<PathBuf> {
+        match claim {
+            None if self.naming_path != self.source && self.naming_path != self.path => vec![self.source.clone(), self.naming_path.clone()],
+            None => vec![self.source.clone()],
+            Some((kind, Some(width))) => vec![self.variant_path(kind, width)],
+            Some((kind, None)) => vec![self.output_path(kind)],
+        }
+    }
+
+    /// Whether the file of `claim` may take another name. Inputs and an original that keeps the
+    /// source's name can't.
+    fn can_rename(&self, claim: Option<(OutputKind, Option<u32>)>) -> bool {
+        match claim {
+            None => false,
+            Some((OutputKind::Original, None)) => !self.keeps_source_name(),
+            Some(_) => true,
+        }
+    }
+
+    /// Path of the `width` pixels wide responsive variant of `kind`, from the variant template.
+    pub fn variant_path(&self, kind: OutputKind, width: u32) -> PathBuf {
+        let ext = match kind {
+            OutputKind::Original => self.path.extension().unwrap_or_default().to_string_lossy(),
+            OutputKind::WebP => "webp".into(),
+            OutputKind::Avif => "avif".into(),
+        };
+        let name = self.settings.variant_template
+            .replace("{name}", &self.stem(&self.naming_path))
+            .replace("{width}", &width.to_string())
+            .replace("{ext}", &ext);
+        self.naming_path.with_file_name(name)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub files: Vec<PlanEntry>,
+    /// Files left out because the cache shows they are already optimized.
+    pub cached: Vec<PathBuf>,
+    /// Outputs several files would have written, and the names they got instead.
+    pub collisions: Vec<NameCollision>,
+    /// WebP files left out as the WebP version of another image.
+    pub earlier_outputs: Vec<PathBuf>,
+    pub single_dir: bool,
+    pub copy_duration: Duration,
+    pub scan_duration: Duration,
+}
+
+pub struct Optimizer {
+    options: OptimizeOptions,
+    cache: Mutex<Option<Cache>>,
+    backup: Option<Backup>,
+}
+
+impl Optimizer {
+    pub fn new(options: OptimizeOptions) -> Self {
+        let backup = options.backup.clone().filter(|_| !options.dry_run).map(Backup::new);
+        Self { options, cache: Mutex::new(None), backup }
+    }
+
+    pub fn options(&self) -> &OptimizeOptions {
+        &self.options
+    }
+
+    pub fn backup(&self) -> Option<&Backup> {
+        self.backup.as_ref()
+    }
+
+    /// Resolves `paths` into the list of files to optimize.
+    ///
+    /// In safe mode this also creates the `__optimized` copies, or with `out_dir` mirrors the
+    /// non-image files. A failed copy of a lone input directory and an invalid config file are
+    /// fatal; copy failures in list mode and of single non-images are reported and skipped.
+    pub fn discover<P, F>(&self, paths: &[P], on_event: F) -> io::Result<Plan>
+    where
+        P: AsRef<Path>,
+        F: Fn(&Event),
+    {
+        // Backed-up originals must never be optimized themselves.
+        if let Some(path) = paths.iter().map(AsRef::as_ref).find(|p| p.join(BACKUP_MARKER).exists() || is_backup_path(p)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is a backup directory, not an input", path.display())));
+        }
+        let replace = self.options.replace;
+        let out_dir = self.options.out_dir.as_deref().filter(|_| !replace);
+        let copy = !replace && !self.options.dry_run && out_dir.is_none();
+        let mirror = out_dir.is_some() && !self.options.dry_run;
+        let mut plan = Plan::default();
+        let scan_start = Instant::now();
+
+        let configs = ConfigResolver::new(
+            self.options.settings.clone(),
+            self.options.overrides.clone(),
+            self.options.config_files,
+            self.options.ignore_files,
+        );
+        let filter = ScanFilter::new(&self.options)?;
+        let cache = self.load_cache(paths, &configs)?;
+        let should_copy = |src: &Path, dst: &Path| match &cache {
+            Some(cache) => !self.is_cached_copy(cache, &configs, src, dst),
+            None => true,
+        };
+
+        plan.single_dir = paths.len() == 1 && paths[0].as_ref().is_dir();
+        if mirror && let Some(out_dir) = out_dir {
+            fs::create_dir_all(out_dir)?;
+        }
+
+        if plan.single_dir {
+            let input_path = paths[0].as_ref();
+            let target_dir = match out_dir {
+                _ if replace => input_path.to_path_buf(),
+                Some(out_dir) => out_dir.to_path_buf(),
+                None => safe_dir_name(input_path, &configs.resolve(input_path)?.settings.output_suffix),
+            };
+            on_event(&Event::DirectoryMode { replace, target: &target_dir });
+
+            if copy {
+                let copy_start = Instant::now();
+                copy_dir_recursive_filtered(input_path, &target_dir, &should_copy)?;
+                plan.copy_duration = copy_start.elapsed();
+                on_event(&Event::CopyFinished { duration: plan.copy_duration });
+            }
+
+            let scan_root = if copy { &target_dir } else { input_path };
+            on_event(&Event::Scanning { dir: scan_root });
+            let mut others = Vec::new();
+            plan.files.extend(scan_dir(scan_root, input_path, &target_dir, &configs, &filter, mirror.then_some(&mut others))?);
+
+            if mirror {
+                let copy_start = Instant::now();
+                self.mirror_non_images(input_path, &target_dir, &others, &on_event);
+                plan.copy_duration = copy_start.elapsed();
+                on_event(&Event::CopyFinished { duration: plan.copy_duration });
+            }
+        } else {
+            on_event(&Event::ListMode);
+            let copy_start = Instant::now();
+
+            for path in paths {
+                let path = path.as_ref();
+                if !path.exists() {
+                    on_event(&Event::NotFound { path });
+                    continue;
+                }
+
+                if path.is_dir() {
+                    let target_dir_root = match out_dir {
+                        _ if replace => path.to_path_buf(),
+                        Some(out_dir) => out_dir.join(path.file_name().unwrap_or_default()),
+                        None => safe_dir_name(path, &configs.resolve(path)?.settings.output_suffix),
+                    };
+                    on_event(&Event::ListDirectory { source: path, target: &target_dir_root });
+
+                    if copy && let Err(error) = copy_dir_recursive_filtered(path, &target_dir_root, &should_copy) {
+                        on_event(&Event::CopyFailed { path, error: &error });
+                        continue;
+                    }
+
+                    let scan_root = if copy { &target_dir_root } else { path };
+                    let mut others = Vec::new();
+                    plan.files.extend(scan_dir(scan_root, path, &target_dir_root, &configs, &filter, mirror.then_some(&mut others))?);
+                    self.mirror_non_images(path, &target_dir_root, &others, &on_event);
+                    continue;
+                }
+
+                let Some(kind) = ImageKind::detect(path) else {
+                    on_event(&Event::Unsupported { path });
+                    continue;
+                };
+
+                let dir_config = configs.resolve_file(path)?;
+                if dir_config.is_excluded(path.file_name().unwrap_or_default()) {
+                    continue;
+                }
+                let settings = dir_config.settings.clone();
+
+                let target_path = if replace {
+                    path.to_path_buf()
+                } else if let Some(out_dir) = out_dir {
+                    out_dir.join(path.file_name().unwrap_or_default())
+                } else {
+                    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                    let new_name = match path.extension() {
+                        Some(ext) => format!("{}{}.{}", stem, settings.output_suffix, ext.to_string_lossy().to_lowercase()),
+                        None => format!("{}{}", stem, settings.output_suffix),
+                    };
+                    path.parent().unwrap_or(Path::new(".")).join(new_name)
+                };
+
+                let naming_path = if replace || out_dir.is_some() {
+                    target_path.clone()
+                } else {
+                    path.to_path_buf()
+                };
+
+                if copy && should_copy(path, &target_path) && let Err(error) = fs::copy(path, &target_path) {
+                    on_event(&Event::CopyFailed { path, error: &error });
+                    continue;
+                }
+                let mut entry = PlanEntry::new(kind, target_path, naming_path, settings);
+                entry = if copy { entry.with_input(path.to_path_buf()) } else { entry.with_source(path.to_path_buf()) };
+                plan.files.push(entry);
+            }
+
+            if copy || mirror {
+                plan.copy_duration = copy_start.elapsed();
+            }
+        }
+
+        plan.earlier_outputs = drop_generated_siblings(&mut plan.files, out_dir.is_none());
+        for path in &plan.earlier_outputs {
+            on_event(&Event::EarlierOutput { path });
+        }
+        plan.collisions = resolve_collisions(&mut plan.files)?;
+        for collision in &plan.collisions {
+            on_event(&Event::NameCollision { collision });
+        }
+
+        if let Some(cache) = &cache {
+            let (cached, pending): (Vec<_>, Vec<_>) = plan.files
+                .into_par_iter()
+                .map(|mut entry| {
+                    entry.hash = hash_file(&entry.source).ok();
+                    let up_to_date = self.is_up_to_date(cache, &entry);
+                    (entry, up_to_date)
+                })
+                .partition(|(_, up_to_date)| *up_to_date);
+            plan.cached = cached.into_iter().map(|(e, _)| e.path).collect();
+            plan.files = pending.into_iter().map(|(e, _)| e).collect();
+        }
+        *self.cache.lock().unwrap() = cache;
+
+        plan.scan_duration = scan_start.elapsed();
+        Ok(plan)
+    }
+
+    /// Puts the non-image files `others` of `dir` at the same relative location under `target`.
+    fn mirror_non_images<F: Fn(&Event)>(&self, dir: &Path, target: &Path, others: &[PathBuf], on_event: &F) {
+        others.iter().for_each(|src| {
+            let dst = target.join(src.strip_prefix(dir).unwrap_or(src));
+            if let Err(error) = mirror_file(src, &dst, self.options.non_images) {
+                on_event(&Event::CopyFailed { path: src, error: &error });
+            }
+        });
+    }
+
+    fn load_cache<P: AsRef<Path>>(&self, paths: &[P], configs: &ConfigResolver) -> io::Result<Option<Cache>> {
+        if !self.options.incremental {
+            return Ok(None);
+        }
+        let path = match &self.options.cache_file {
+            Some(p) => p.clone(),
+            None => {
+                let Some(first) = paths.iter().map(|p| p.as_ref()).find(|p| p.exists()) else { return Ok(None) };
+                if let Some(out_dir) = self.options.out_dir.as_deref().filter(|_| !self.options.replace) {
+                    default_cache_path(out_dir)
+                } else if first.is_dir() {
+                    let target = if self.options.replace {
+                        first.to_path_buf()
+                    } else {
+                        safe_dir_name(first, &configs.resolve(first)?.settings.output_suffix)
+                    };
+                    default_cache_path(&target)
+                } else {
+                    first.parent().unwrap_or(Path::new(".")).join(CACHE_FILE_NAME)
+                }
+            }
+        };
+        Ok(Some(Cache::load(&path)))
+    }
+
+    /// Safe mode: true when `dst` already holds the cached optimization of `src`, so the copy can be skipped.
+    fn is_cached_copy(&self, cache: &Cache, configs: &ConfigResolver, src: &Path, dst: &Path) -> bool {
+        let Some(kind) = ImageKind::detect(src) else { return false };
+        let Ok(dir_config) = configs.resolve_file(src) else { return false };
+        // A renamed copy only survives under its new extension.
+        let dst = dir_config.settings.original_path(kind, dst);
+        if !dst.exists() {
+            return false;
+        }
+        let Ok(src_hash) = hash_file(src) else { return false };
+        let Some(expected) = cache.output_for(&src_hash, &dir_config.settings.cache_settings(kind)) else { return false };
+        hash_file(&dst).is_ok_and(|h| h == expected)
+    }
+
+    fn is_up_to_date(&self, cache: &Cache, entry: &PlanEntry) -> bool {
+        let Some(hash) = &entry.hash else { return false };
+        // A source that still exists hasn't been renamed yet.
+        if entry.renamed {
+            return false;
+        }
+        let settings = entry.settings.cache_settings(entry.kind);
+        let done = if entry.source == entry.path {
+            cache.is_optimized(hash, &settings)
+        } else {
+            // Written out of place, the source never changes; the optimized original must still be there.
+            cache.output_for(hash, &settings).is_some_and(|expected| hash_file(&entry.path).is_ok_and(|h| h == expected))
+        };
+        done && (!entry.settings.webp || entry.output_path(OutputKind::WebP).exists())
+            && (!entry.settings.avif || entry.output_path(OutputKind::Avif).exists())
+    }
+
+    fn record_in_cache(&self, entry: &PlanEntry, result: &FileResult) {
+        let Some(input) = &entry.hash else { return };
+        if result.is_failed() || self.options.dry_run {
+            return;
+        }
+        let Ok(output) = hash_file(&entry.path) else { return };
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.insert(input.clone(), entry.settings.cache_settings(result.kind), output.clone());
+            if entry.renamed {
+                // Later runs find the renamed file itself; it needs no further work in its new format.
+                cache.insert(output.clone(), entry.settings.cache_settings(entry.format), output);
+            }
+        }
+    }
+
+    /// Optimizes every file of `plan` in parallel.
+    pub fn process<F>(&self, plan: &Plan, on_event: F) -> RunSummary
+    where
+        F: Fn(&Event) + Sync,
+    {
+        let process_start = Instant::now();
+        let results: Vec<FileResult> = plan.files
+            .par_iter()
+            .map(|entry| {
+                on_event(&Event::FileStarted { path: &entry.path });
+                let result = self.process_file(entry);
+                self.record_in_cache(entry, &result);
+                on_event(&Event::FileFinished { result: &result });
+                result
+            })
+            .collect();
+
+        if let Some(cache) = self.cache.lock().unwrap().as_ref()
+            && !self.options.dry_run
+            && let Err(error) = cache.save() {
+            on_event(&Event::CacheSaveFailed { path: cache.path(), error: &error });
+        }
+
+        RunSummary::from_results(results, process_start.elapsed())
+    }
+
+    /// Discovers and processes `paths` in one call.
+    pub fn run<P, F>(&self, paths: &[P], on_event: F) -> io::Result<(Plan, RunSummary)>
+    where
+        P: AsRef<Path>,
+        F: Fn(&Event) + Sync,
+    {
+        let plan = self.discover(paths, &on_event)?;
+        let summary = self.process(&plan, &on_event);
+        Ok((plan, summary))
+    }
+
+    pub fn process_file(&self, entry: &PlanEntry) -> FileResult {
+        let opts = &*entry.settings;
+        let path = &entry.path;
+        let (kind, format, extension_mismatch) = (entry.kind, entry.format, entry.extension_mismatch);
+        let data = match fs::read(&entry.source) {
+            Ok(d) => d,
+            Err(e) => {
+                let outputs = vec![failed_output(OutputKind::Original, path.clone(), 0, e.into())];
+                return FileResult { path: path.clone(), source: entry.input.clone(), kind, format, extension_mismatch, original_size: 0, dimensions: None, resized: None, outputs };
+            }
+        };
+        let original_size = data.len() as u64;
+
+        // Decoded once; every encoder below works from these (possibly downscaled) pixels.
+        let img = match image::load_from_memory(&data) {
+            Ok(img) => img,
+            Err(e) => {
+                let message = e.to_string();
+                let outputs = entry.output_kinds().into_iter()
+                    .map(|k| failed_output(k, entry.output_path(k), original_size, OptimizeError::Decode(message.clone())))
+                    .collect();
+                return FileResult { path: path.clone(), source: entry.input.clone(), kind, format, extension_mismatch, original_size, dimensions: None, resized: None, outputs };
+            }
+        };
+        // Animations survive only when GIF stays GIF; everything else takes the first frame.
+        let frames = match (kind, format) {
+            (ImageKind::Gif, ImageKind::Gif) => decode_gif_frames(&data).unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        // Re-encoding keeps only the first frame, so an animated WebP or multi-page TIFF that keeps
+        // its format is passed through with only its metadata rewritten, and its orientation and
+        // profile are left alone to keep describing its frames. Other outputs take the first frame.
+        let pages = kind == ImageKind::Tiff && ImageKind::is_multi_page_tiff(&data);
+        let animated = (format == ImageKind::WebP && ImageKind::is_animated_webp(&data)) || (pages && !entry.is_converted());
+        let source_metadata = Metadata::read(&data);
+        // Pixels are stored as shot; the EXIF orientation says how to display them.
+        let orientation = source_metadata.orientation().filter(|&o| o != 1 && !animated);
+        let img = match orientation {
+            Some(o) => apply_orientation(img, o),
+            None => img,
+        };
+        let keep = MetadataPolicy::keep(&opts.metadata);
+        let mut metadata = source_metadata.filter(keep);
+        if orientation.is_some() {
+            metadata.reset_orientation();
+        }
+        // A profile the policy keeps must go on describing the pixels, so they are left alone.
+        let converted = match (opts.color, &source_metadata.icc) {
+            (ColorMode::Srgb, Some(icc)) if !animated && !keep.icc => convert_to_srgb(&img, icc),
+            _ => None,
+        };
+        let recolored = converted.is_some();
+        let img = match converted {
+            Some(srgb) => {
+                // The source profile no longer describes the pixels.
+                metadata.icc = None;
+                srgb
+            }
+            None => img,
+        };
+        if opts.color == ColorMode::Preserve || animated {
+            metadata.icc = source_metadata.icc.clone();
+        }
+        let repeat = gif_repeat(&data);
+        let lossless = kind == ImageKind::WebP && format == ImageKind::WebP && ImageKind::is_lossless_webp(&data);
+        let info = SourceInfo { format, size: original_size, metadata, frames, repeat, lossless };
+        let (width, height) = (img.width(), img.height());
+        let mut resized = None;
+        let img = match fit_dimensions(width, height, opts.max_width, opts.max_height, opts.max_pixels) {
+            Some((w, h)) => {
+                resized = Some(Resized {
+                    from: Dimensions { width, height },
+                    to: Dimensions { width: w, height: h },
+                });
+                resize(&img, w, h, opts.resize_filter)
+            }
+            None => img,
+        };
+
+        let mut outputs = Vec::new();
+        for format in [OutputKind::WebP, OutputKind::Avif] {
+            if entry.output_kinds().contains(&format) {
+                outputs.push(self.write_output(format, &info, &img, opts, entry.output_path(format)));
+            }
+        }
+
+        let mut tuned = None;
+        let original = timed_output(OutputKind::Original, path.clone(), original_size, original_size, |p| {
+            // Unless the pixels changed, oxipng can start from the original bytes rather than a re-encode.
+            let transformed = resized.is_some() || orientation.is_some() || recolored;
+            let source = (!transformed && !entry.is_converted()).then_some(&data[..]);
+            if pages && entry.is_converted() {
+                return Err(OptimizeError::Encode(format!("multi-page TIFF; converting it to {} would keep only the first page, so it is left as it is", format)));
+            }
+            let (encoded, t) = if animated { (Vec::new(), None) } else { encode_output(OutputKind::Original, &info, &img, source, opts)? };
+            // A downscaled, rotated or converted image is written even in the rare case it came out larger.
+            let size = if !encoded.is_empty() && (transformed || entry.is_converted() || encoded.len() < data.len()) {
+                tuned = t;
+                self.emit(p, &encoded)?
+            } else {
+                // The original stays, but its metadata still follows the policy.
+                let retagged = apply_metadata(kind, &info.metadata, &data)?;
+                // Out of place, the output doesn't hold the image yet.
+                if retagged != data || entry.source != entry.path {
+                    self.emit(p, &retagged)?
+                } else {
+                    original_size
+                }
+            };
+            if entry.renamed {
+                self.remove(&entry.source)?;
+            }
+            Ok(size)
+        });
+        outputs.push(OutputResult { tuned, ..original });
+
+        outputs.extend(self.encode_variants(entry, &info, &img));
+
+        let dimensions = Some(Dimensions { width: img.width(), height: img.height() });
+        FileResult { path: path.clone(), source: entry.input.clone(), kind, format, extension_mismatch, original_size, dimensions, resized, outputs }
+    }
+
+    /// Downscaled copies of `img` for every configured width below its own, in every enabled format.
+    fn encode_variants(&self, entry: &PlanEntry, info: &SourceInfo, img: &DynamicImage) -> Vec<OutputResult> {
+        let opts = &*entry.settings;
+        let mut outputs = Vec::new();
+        let mut widths = opts.widths.clone();
+        widths.sort_unstable();
+        widths.dedup();
+        for width in widths.into_iter().filter(|&w| w < img.width()) {
+            let height = ((img.height() as u64 * width as u64 + img.width() as u64 / 2) / img.width() as u64).max(1) as u32;
+            let scaled = resize(img, width, height, opts.resize_filter);
+            let variant = Some(Dimensions { width, height });
+
+            for format in entry.output_kinds() {
+                let out = self.write_output(format, info, &scaled, opts, entry.variant_path(format, width));
+                outputs.push(OutputResult { variant, ..out });
+            }
+        }
+        outputs
+    }
+
+    /// Encodes `img` as `format` into `path`, timing the whole thing.
+    fn write_output(&self, format: OutputKind, info: &SourceInfo, img: &DynamicImage, opts: &FileSettings, path: PathBuf) -> OutputResult {
+        let mut tuned = None;
+        let out = timed_output(format, path, info.size, 0, |p| {
+            let (encoded, t) = encode_output(format, info, img, None, opts)?;
+            tuned = t;
+            self.emit(p, &encoded)
+        });
+        OutputResult { tuned, ..out }
+    }
+
+    /// Writes `data` to `path`, unless this is a dry run. Returns the (projected) size.
+    fn emit(&self, path: &Path, data: &[u8]) -> Result<u64, OptimizeError> {
+        if !self.options.dry_run {
+            // An `out_dir` mirror only has the directories non-images were put in yet.
+            if let Some(parent) = path.parent() && !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+            if let Some(backup) = &self.backup {
+                backup.before_change(path)?;
+            }
+            write_atomic(path, data)?;
+            if let Some(backup) = &self.backup {
+                backup.after_write(path, data)?;
+            }
+        }
+        Ok(data.len() as u64)
+    }
+
+    /// Removes a source that was replaced under a new name, unless this is a dry run.
+    fn remove(&self, path: &Path) -> Result<(), OptimizeError> {
+        if !self.options.dry_run {
+            if let Some(backup) = &self.backup {
+                backup.before_change(path)?;
+            }
+            fs::remove_file(path)?;
+            if let Some(backup) = &self.backup {
+                backup.after_remove(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Encodes `img` as `format`. With a perceptual target or byte limit, the quality of lossy formats
+/// is searched instead of taken from the settings. `source` is the unmodified input, when it may be reused.
+fn encode_output(format: OutputKind, info: &SourceInfo, img: &DynamicImage, source: Option<&[u8]>, opts: &FileSettings) -> Result<(Vec<u8>, Option<Tuned>), OptimizeError> {
+    let tuning = opts.tuning();
+    let metadata = &info.metadata;
+    match (format, info.format) {
+        (OutputKind::Original, ImageKind::Png) => {
+            Ok((metadata.apply_png(&encode_png_image(img, source, &opts.png_options())?)?, None))
+        }
+        (OutputKind::Original, ImageKind::Jpeg) => {
+            tune(img, opts.jpg_quality, &tuning, true, |img, q| metadata.apply_jpeg(&encode_jpg_image(img, q)?))
+        }
+        (OutputKind::Original, ImageKind::Gif) => Ok((encode_gif(img, &info.frames, info.repeat, opts.resize_filter)?, None)),
+        (OutputKind::Original, ImageKind::Tiff) => Ok((encode_as(img, ImageFormat::Tiff)?, None)),
+        (OutputKind::Original, ImageKind::Bmp) => Ok((encode_as(img, ImageFormat::Bmp)?, None)),
+        (OutputKind::Original, ImageKind::Qoi) => Ok((encode_as(img, ImageFormat::Qoi)?, None)),
+        // A lossless WebP stays lossless; there is no quality to search.
+        (OutputKind::Original, ImageKind::WebP) if info.lossless => Ok((metadata.apply_webp(&encode_webp_lossless(img)?)?, None)),
+        (OutputKind::WebP, _) | (OutputKind::Original, ImageKind::WebP) => {
+            tune(img, WEBP_QUALITY as u8, &tuning, true, |img, q| metadata.apply_webp(&encode_webp(img, q as f32)?))
+        }
+        // AVIF can't be decoded in-process to be measured, so only the byte limit applies.
+        (OutputKind::Avif, _) => tune(img, AVIF_QUALITY as u8, &tuning, false, |img, q| encode_avif(img, q as f32, metadata)),
+    }
+}
+
+/// Rewrites the metadata of an encoded file of `kind` to `metadata`. Formats without metadata
+/// support are returned as they are.
+fn apply_metadata(kind: ImageKind, metadata: &Metadata, data: &[u8]) -> Result<Vec<u8>, OptimizeError> {
+    match kind {
+        ImageKind::Jpeg => metadata.apply_jpeg(data),
+        ImageKind::Png => metadata.apply_png(data),
+        ImageKind::WebP => metadata.apply_webp(data),
+        ImageKind::Gif | ImageKind::Tiff | ImageKind::Bmp | ImageKind::Qoi => Ok(data.to_vec()),
+    }
+}
+
+/// What every output of one source file shares.
+struct SourceInfo {
+    /// Format of the optimized original.
+    format: ImageKind,
+    size: u64,
+    /// The source's metadata, already filtered by the policy.
+    metadata: Metadata,
+    /// All frames of an animated GIF that stays GIF; empty otherwise.
+    frames: Vec<Frame>,
+    /// How often that GIF repeats; `None` plays it once.
+    repeat: Option<Repeat>,
+    /// The source is a lossless WebP that stays WebP.
+    lossless: bool,
+}
+
+/// Runs one encoder and records its outcome. `size_on_error` is what is left on disk if it fails.
+fn timed_output<F>(kind: OutputKind, path: PathBuf, original_size: u64, size_on_error: u64, encode: F) -> OutputResult
+where
+    F: FnOnce(&Path) -> Result<u64, OptimizeError>,
+{
+    let t = Instant::now();
+    let res = encode(&path);
+    let duration = t.elapsed();
+    let (new_size, error) = match res {
+        Ok(size) => (size, None),
+        Err(e) => (size_on_error, Some(e)),
+    };
+    OutputResult { kind, path, original_size, new_size, variant: None, tuned: None, duration, error }
+}
+
+fn failed_output(kind: OutputKind, path: PathBuf, original_size: u64, error: OptimizeError) -> OutputResult {
+    OutputResult { kind, path, original_size, new_size: 0, variant: None, tuned: None, duration: Duration::ZERO, error: Some(error) }
+}
+
+/// Removes WebP files lying where another image writes its WebP version (`logo.webp` next to
+/// `logo.jpg` with `webp` on): they are output of an earlier run, and recompressing them would only
+/// lose quality. Nothing is removed when `in_place` is false: an `out_dir` input tree holds no
+/// outputs. Returns the sources of the removed entries.
+pub(crate) fn drop_generated_siblings(files: &mut Vec<PlanEntry>, in_place: bool) -> Vec<PathBuf> {
+    if !in_place {
+        return Vec::new();
+    }
+    let mut siblings = HashSet::new();
+    for entry in files.iter().filter(|e| e.output_kinds().contains(&OutputKind::WebP)) {
+        let mut renamed = entry.clone();
+        renamed.disambiguated = true;
+        siblings.insert(collision_key(&entry.output_path(OutputKind::WebP)));
+        siblings.insert(collision_key(&renamed.output_path(OutputKind::WebP)));
+    }
+    let mut dropped = Vec::new();
+    files.retain(|e| {
+        let generated = e.kind == ImageKind::WebP
+            && e.source.file_name() == e.path.file_name()
+            && siblings.contains(&collision_key(&e.path));
+        if generated {
+            dropped.push(e.source.clone());
+        }
+        !generated
+    });
+    dropped
+}
+
+/// Finds files that several entries would write, like `logo.webp` from both `logo.png` and
+/// `logo.jpg` or `pic.png` from `pic.tiff` and `pic.png`, and disambiguates every entry involved
+/// that can choose its names. Fails when that isn't enough, e.g. two inputs named alike copied
+/// into one `out_dir`, or a `collision_name` without `{source_ext}`.
+pub(crate) fn resolve_collisions(files: &mut [PlanEntry]) -> io::Result<Vec<NameCollision>> {
+    type Claimants = Vec<(usize, Option<(OutputKind, Option<u32>)>)>;
+    let mut collisions = Vec::new();
+    for pass in 0..2 {
+        let mut claims: BTreeMap<PathBuf, (PathBuf, Claimants)> = BTreeMap::new();
+        for (i, entry) in files.iter().enumerate() {
+            for claim in entry.claims() {
+                for path in entry.claim_paths(claim) {
+                    let (_, claimants) = claims.entry(collision_key(&path)).or_insert_with(|| (path, Vec::new()));
+                    if !claimants.iter().any(|(j, _)| *j == i) {
+                        claimants.push((i, claim));
+                    }
+                }
+            }
+        }
+        let contested: Vec<_> = claims.into_values().filter(|(_, claimants)| claimants.len() > 1).collect();
+        if contested.is_empty() {
+            break;
+        }
+        if pass == 1 {
+            let (path, claimants) = &contested[0];
+            let sources: Vec<String> = claimants.iter().map(|(i, _)| files[*i].source.display().to_string()).collect();
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                "{} would be written for each of {}; give them different names or use a --collision-name with {{source_ext}}",
+                path.display(), sources.join(", ")
+            )));
+        }
+        for (_, claimants) in &contested {
+            // A single source that keeps its own name (`pic.png` among `pic.tiff` and `pic.bmp`)
+            // keeps its plain names too, so they stay the same once the others are converted.
+            let keepers: HashSet<usize> = claimants.iter().map(|(i, _)| *i).filter(|&i| files[i].keeps_source_name()).collect();
+            for &(i, claim) in claimants {
+                if files[i].can_rename(claim) && !(keepers.len() == 1 && keepers.contains(&i)) {
+                    files[i].disambiguate();
+                }
+            }
+        }
+        for (path, claimants) in contested {
+            let sources = claimants.iter().map(|(i, _)| files[*i].source.clone()).collect();
+            let outputs = claimants.iter().map(|&(i, claim)| match claim {
+                None => path.clone(),
+                Some(_) => files[i].claim_paths(claim).remove(0),
+            }).collect();
+            collisions.push(NameCollision { path, sources, outputs });
+        }
+    }
+    Ok(collisions)
+}
+
+/// Paths that name the same file. Windows and macOS file systems ignore case by default.
+fn collision_key(path: &Path) -> PathBuf {
+    if cfg!(any(windows, target_os = "macos")) {
+        PathBuf::from(path.to_string_lossy().to_lowercase())
+    } else {
+        path.to_path_buf()
+    }
+}
+
+pub(crate) fn safe_dir_name(path: &Path, suffix: &str) -> PathBuf {
+    let root_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let new_name = format!("{}{}", root_name, suffix);
+    path.parent().unwrap_or(Path::new(".")).join(new_name)
+}
+
+/// Scans `dir` for images. Each one is planned at the same relative location under `target`, and
+/// recorded as coming from that location under `input`, the directory `dir` is a copy of (or `dir`).
+/// Other files go to `others`, when given; filtered out ones don't. `target` itself is skipped if
+/// it lies inside `dir`.
+fn scan_dir(dir: &Path, input: &Path, target: &Path, configs: &ConfigResolver, filter: &ScanFilter, mut others: Option<&mut Vec<PathBuf>>) -> io::Result<Vec<PlanEntry>> {
+    let mut files = Vec::new();
+    let target_canonical = if dir == target { None } else { fs::canonicalize(target).ok() };
+    let mut walker = WalkDir::new(dir)
+        .follow_links(filter.follow_links)
+        .max_depth(filter.max_depth.unwrap_or(usize::MAX))
+        .into_iter();
+    let mut current: Option<(PathBuf, Arc<DirConfig>)> = None;
+
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else { continue };
+        if entry.depth() == 0 || (!filter.follow_links && entry.path_is_symlink()) {
+            continue;
+        }
+        let parent = entry.path().parent().unwrap_or(dir);
+        let dir_config = match &current {
+            Some((p, c)) if p == parent => c.clone(),
+            _ => {
+                let c = configs.resolve(parent)?;
+                current = Some((parent.to_path_buf(), c.clone()));
+                c
+            }
+        };
+
+        let is_dir = entry.file_type().is_dir();
+        let rel = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        let filtered = if is_dir { filter.skips_dir(rel) } else { filter.skips_file(rel) };
+        if filtered || dir_config.is_excluded(entry.file_name()) || dir_config.is_ignored(entry.file_name(), is_dir) {
+            if is_dir {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+        if is_dir {
+            if entry.path().join(BACKUP_MARKER).exists()
+                || target_canonical.is_some() && fs::canonicalize(entry.path()).ok() == target_canonical {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+        if is_temp_file(entry.file_name()) || entry.file_name().to_string_lossy().ends_with(CACHE_FILE_NAME) {
+            continue;
+        }
+        let kind = if dir_config.settings.is_variant_name(entry.file_name()) { None } else { ImageKind::detect(entry.path()) };
+        match kind {
+            Some(kind) => {
+                let source = entry.into_path();
+                let rel = source.strip_prefix(dir).unwrap_or(&source);
+                let (p, original) = (target.join(rel), input.join(rel));
+                files.push(PlanEntry::new(kind, p.clone(), p, dir_config.settings.clone()).with_source(source).with_input(original));
+            }
+            None => if let Some(others) = others.as_deref_mut() {
+                others.push(entry.into_path());
+            },
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webp_settings() -> Arc<FileSettings> {
+        Arc::new(FileSettings { webp: true, ..FileSettings::default() })
+    }
+
+    fn entry(path: &str, kind: ImageKind, settings: &Arc<FileSettings>) -> PlanEntry {
+        PlanEntry::new(kind, PathBuf::from(path), PathBuf::from(path), settings.clone())
+    }
+
+    fn outputs(entry: &PlanEntry) -> Vec<PathBuf> {
+        entry.output_kinds().into_iter().map(|kind| entry.output_path(kind)).collect()
+    }
+
+    /// An empty directory of its own for one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("images-optimizer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A 16x16 WebP animation of three differently colored frames.
+    fn animated_webp() -> Vec<u8> {
+        let config = webp::WebPConfig::new().unwrap();
+        let frames: Vec<Vec<u8>> = (0..3u8).map(|i| [i * 80, 0, 255 - i * 80, 255].repeat(16 * 16)).collect();
+        let mut encoder = webp::AnimEncoder::new(16, 16, &config);
+        for (i, frame) in frames.iter().enumerate() {
+            encoder.add_frame(webp::AnimFrame::from_rgba(frame, 16, 16, i as i32 * 100));
+        }
+        encoder.encode().to_vec()
+    }
+
+    #[test]
+    fn backup_directories_are_refused_as_inputs() {
+        let dir = temp_dir("backup-input");
+        let backup = Backup::new(dir.join("bk"));
+        let image = dir.join("x.png");
+        fs::write(&image, b"png").unwrap();
+        backup.before_change(&image).unwrap();
+        let run = backup.run_dir().unwrap();
+
+        let optimizer = Optimizer::new(OptimizeOptions { replace: true, ..Default::default() });
+        for input in [dir.join("bk"), run.clone(), run.join("files")] {
+            let error = optimizer.discover(&[&input], |_| {}).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{}", input.display());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A two-page TIFF: the second IFD is a copy of the first, sharing its pixel data.
+    fn multi_page_tiff() -> Vec<u8> {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb([200, 10, 10])));
+        let mut data = encode_as(&img, ImageFormat::Tiff).unwrap();
+        assert!(!ImageKind::is_multi_page_tiff(&data));
+        let ifd0 = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        let count = u16::from_le_bytes(data[ifd0..ifd0 + 2].try_into().unwrap()) as usize;
+        let next = ifd0 + 2 + count * 12;
+        let ifd1 = data.len() + data.len() % 2;
+        let copy = data[ifd0..next + 4].to_vec();
+        data.resize(ifd1, 0);
+        data.extend_from_slice(&copy);
+        data[next..next + 4].copy_from_slice(&(ifd1 as u32).to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn multi_page_tiff_is_never_flattened() {
+        let dir = temp_dir("multi-page-tiff");
+        let path = dir.join("scan.tiff");
+        let data = multi_page_tiff();
+        assert!(ImageKind::is_multi_page_tiff(&data));
+        let optimizer = Optimizer::new(OptimizeOptions { replace: true, ..Default::default() });
+
+        fs::write(&path, &data).unwrap();
+        let result = optimizer.process_file(&PlanEntry::new(ImageKind::Tiff, path.clone(), path.clone(), Arc::new(FileSettings::default())));
+        assert!(result.outputs.iter().all(|o| o.error.is_none()), "{:?}", result.outputs);
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        let settings = Arc::new(FileSettings { convert: BTreeMap::from([(ImageKind::Tiff, FormatPolicy::Png)]), ..FileSettings::default() });
+        let result = optimizer.process_file(&PlanEntry::new(ImageKind::Tiff, path.clone(), path.clone(), settings));
+        assert!(result.outputs[0].error.as_ref().unwrap().to_string().contains("multi-page"));
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(!dir.join("scan.png").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lossless_webp_stays_lossless() {
+        let dir = temp_dir("lossless-webp");
+        let path = dir.join("flat.webp");
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(32, 32, |x, y| image::Rgba([(x * 8) as u8, (y * 8) as u8, 0, 255])));
+        let data = encode_webp_lossless(&img).unwrap();
+        assert!(ImageKind::is_lossless_webp(&data));
+        fs::write(&path, &data).unwrap();
+
+        let result = Optimizer::new(OptimizeOptions::default())
+            .process_file(&PlanEntry::new(ImageKind::WebP, path.clone(), path.clone(), Arc::new(FileSettings::default())));
+        assert!(result.outputs.iter().all(|o| o.error.is_none()));
+
+        let written = fs::read(&path).unwrap();
+        assert!(ImageKind::is_lossless_webp(&written));
+        assert_eq!(image::load_from_memory(&written).unwrap().to_rgba8(), img.to_rgba8());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn animated_webp_keeps_its_frames() {
+        let dir = temp_dir("animated-webp");
+        let path = dir.join("anim.webp");
+        let data = animated_webp();
+        assert!(ImageKind::is_animated_webp(&data));
+        fs::write(&path, &data).unwrap();
+
+        let settings = Arc::new(FileSettings { avif: true, ..FileSettings::default() });
+        let result = Optimizer::new(OptimizeOptions::default())
+            .process_file(&PlanEntry::new(ImageKind::WebP, path.clone(), path.clone(), settings));
+        assert!(result.outputs.iter().all(|o| o.error.is_none()));
+
+        let written = fs::read(&path).unwrap();
+        assert!(ImageKind::is_animated_webp(&written));
+        assert_eq!(webp::AnimDecoder::new(&written).decode().unwrap().len(), 3);
+        // The AVIF version is a still of the first frame.
+        assert!(dir.join("anim.avif").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn same_stem_sources_get_distinct_webp_names() {
+        let settings = webp_settings();
+        let mut files = vec![entry("d/logo.png", ImageKind::Png, &settings), entry("d/logo.jpg", ImageKind::Jpeg, &settings)];
+        let collisions = resolve_collisions(&mut files).unwrap();
+
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].path, PathBuf::from("d/logo.webp"));
+        assert_eq!(collisions[0].outputs, [PathBuf::from("d/logo.png.webp"), PathBuf::from("d/logo.jpg.webp")]);
+        assert_eq!(outputs(&files[0]), [PathBuf::from("d/logo.png"), PathBuf::from("d/logo.png.webp")]);
+        assert_eq!(outputs(&files[1]), [PathBuf::from("d/logo.jpg"), PathBuf::from("d/logo.jpg.webp")]);
+    }
+
+    #[test]
+    fn collision_name_template_is_applied() {
+        let settings = Arc::new(FileSettings { webp: true, collision_name: "{name}-{source_ext}".into(), ..FileSettings::default() });
+        let mut files = vec![entry("logo.png", ImageKind::Png, &settings), entry("logo.jpg", ImageKind::Jpeg, &settings)];
+        resolve_collisions(&mut files).unwrap();
+
+        assert_eq!(files[0].output_path(OutputKind::WebP), PathBuf::from("logo-png.webp"));
+        assert_eq!(files[1].output_path(OutputKind::WebP), PathBuf::from("logo-jpg.webp"));
+    }
+
+    #[test]
+    fn converted_original_yields_to_existing_png() {
+        let settings = Arc::new(FileSettings { webp: true, convert: BTreeMap::from([(ImageKind::Tiff, FormatPolicy::Png)]), ..FileSettings::default() });
+        let mut files = vec![entry("pic.tiff", ImageKind::Tiff, &settings), entry("pic.png", ImageKind::Png, &settings)];
+        assert_eq!(files[0].path, PathBuf::from("pic.png"));
+        let collisions = resolve_collisions(&mut files).unwrap();
+
+        assert_eq!(collisions.len(), 2);
+        assert_eq!(outputs(&files[0]), [PathBuf::from("pic.tiff.png"), PathBuf::from("pic.tiff.webp")]);
+        // The png keeps its own name, and with it the plain WebP name.
+        assert!(!files[1].disambiguated);
+        assert_eq!(outputs(&files[1]), [PathBuf::from("pic.png"), PathBuf::from("pic.webp")]);
+    }
+
+    #[test]
+    fn variants_are_disambiguated_too() {
+        let settings = Arc::new(FileSettings { webp: true, widths: vec![320], ..FileSettings::default() });
+        let mut files = vec![entry("a.jpg", ImageKind::Jpeg, &settings), entry("a.png", ImageKind::Png, &settings)];
+        resolve_collisions(&mut files).unwrap();
+
+        assert_eq!(files[0].variant_path(OutputKind::WebP, 320), PathBuf::from("a.jpg-320w.webp"));
+        assert_eq!(files[1].variant_path(OutputKind::WebP, 320), PathBuf::from("a.png-320w.webp"));
+        // Originals and their variants keep their own extension, so they never clashed.
+        assert_eq!(files[0].path, PathBuf::from("a.jpg"));
+    }
+
+    #[test]
+    fn no_collision_leaves_names_alone() {
+        let settings = webp_settings();
+        let mut files = vec![entry("a.png", ImageKind::Png, &settings), entry("b.png", ImageKind::Png, &settings)];
+        assert!(resolve_collisions(&mut files).unwrap().is_empty());
+        assert!(files.iter().all(|f| !f.disambiguated));
+    }
+
+    #[test]
+    fn same_name_inputs_in_one_out_dir_fail() {
+        let settings = webp_settings();
+        let mut files = vec![
+            entry("o/x.png", ImageKind::Png, &settings).with_source(PathBuf::from("a/x.png")),
+            entry("o/x.png", ImageKind::Png, &settings).with_source(PathBuf::from("b/x.png")),
+        ];
+        let error = resolve_collisions(&mut files).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(error.to_string().contains("o/x.png"));
+    }
+
+    #[test]
+    fn collision_name_without_source_ext_fails() {
+        let settings = Arc::new(FileSettings { webp: true, collision_name: "{name}_x".into(), ..FileSettings::default() });
+        let mut files = vec![entry("logo.png", ImageKind::Png, &settings), entry("logo.jpg", ImageKind::Jpeg, &settings)];
+        let error = resolve_collisions(&mut files).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(error.to_string().contains("logo_x.webp"));
+    }
+
+    #[test]
+    fn earlier_webp_siblings_are_dropped_only_when_webp_is_generated() {
+        let plain = Arc::new(FileSettings::default());
+        let mut files = vec![entry("logo.png", ImageKind::Png, &plain), entry("logo.webp", ImageKind::WebP, &plain)];
+        assert!(drop_generated_siblings(&mut files, true).is_empty());
+        assert_eq!(files.len(), 2);
+
+        let settings = webp_settings();
+        let mut files = vec![entry("logo.png", ImageKind::Png, &settings), entry("logo.webp", ImageKind::WebP, &settings)];
+        assert!(drop_generated_siblings(&mut files, false).is_empty());
+        assert_eq!(drop_generated_siblings(&mut files, true), [PathBuf::from("logo.webp")]);
+        assert_eq!(files.len(), 1);
+    }
+}
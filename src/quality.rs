@@ -0,0 +1,301 @@
+use image::{DynamicImage, GrayImage};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::error::OptimizeError;
+use crate::image_ops::{ResizeFilter, resize};
+use crate::results::{Dimensions, Tuned};
+
+pub const DEFAULT_MIN_QUALITY: u8 = 10;
+/// Width factor per step when downscaling to fit `max_bytes`.
+const SHRINK_STEP: f64 = 0.85;
+
+const WINDOW: u32 = 8;
+const STEP: u32 = 4;
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// A perceptual similarity every lossy output must reach against its source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QualityTarget {
+    /// Minimum SSIM, 0 to 1. Higher is stricter.
+    Ssim(f64),
+    /// Maximum DSSIM (`1 / SSIM - 1`). Lower is stricter.
+    Dssim(f64),
+}
+
+impl QualityTarget {
+    pub fn is_met(self, ssim: f64) -> bool {
+        match self {
+            QualityTarget::Ssim(min) => ssim >= min,
+            QualityTarget::Dssim(max) => dssim(ssim) <= max,
+        }
+    }
+}
+
+impl fmt::Display for QualityTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QualityTarget::Ssim(v) => write!(f, "ssim>={}", v),
+            QualityTarget::Dssim(v) => write!(f, "dssim<={}", v),
+        }
+    }
+}
+
+pub fn dssim(ssim: f64) -> f64 {
+    if ssim > 0.0 { 1.0 / ssim - 1.0 } else { f64::INFINITY }
+}
+
+/// Mean SSIM of the luma channels of `a` and `b`, over 8x8 windows. Both must have the same size.
+pub fn ssim(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    ssim_luma(&a.to_luma8(), &b.to_luma8())
+}
+
+fn ssim_luma(a: &GrayImage, b: &GrayImage) -> f64 {
+    let (width, height) = a.dimensions();
+    if (width, height) != b.dimensions() {
+        return 0.0;
+    }
+    let (win_w, win_h) = (WINDOW.min(width), WINDOW.min(height));
+    let n = (win_w * win_h) as f64;
+    let mut total = 0.0;
+    let mut windows = 0u32;
+
+    let mut y = 0;
+    while y + win_h <= height {
+        let mut x = 0;
+        while x + win_w <= width {
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for dy in 0..win_h {
+                for dx in 0..win_w {
+                    let pa = a.get_pixel(x + dx, y + dy).0[0] as f64;
+                    let pb = b.get_pixel(x + dx, y + dy).0[0] as f64;
+                    sa += pa;
+                    sb += pb;
+                    saa += pa * pa;
+                    sbb += pb * pb;
+                    sab += pa * pb;
+                }
+            }
+            let (ma, mb) = (sa / n, sb / n);
+            let va = saa / n - ma * ma;
+            let vb = sbb / n - mb * mb;
+            let cov = sab / n - ma * mb;
+            total += ((2.0 * ma * mb + C1) * (2.0 * cov + C2)) / ((ma * ma + mb * mb + C1) * (va + vb + C2));
+            windows += 1;
+            x += STEP;
+        }
+        y += STEP;
+    }
+    if windows == 0 { 1.0 } else { total / windows as f64 }
+}
+
+/// How lossy outputs pick their quality when it isn't simply fixed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tuning {
+    pub target: Option<QualityTarget>,
+    pub max_bytes: Option<u64>,
+    /// Lowest quality any search may go down to.
+    pub min_quality: u8,
+    /// With `max_bytes`, images may be downscaled down to this width when even `min_quality` is too large.
+    pub min_width: Option<u32>,
+    pub filter: ResizeFilter,
+}
+
+impl Tuning {
+    pub fn is_active(&self) -> bool {
+        self.target.is_some() || self.max_bytes.is_some()
+    }
+}
+
+/// Encodes `img` through `encode(img, quality)`. Without tuning this is `default_quality`;
+/// otherwise quality is searched as described by `tuning`. `measurable` is false for formats
+/// that can't be decoded again, which then ignore the perceptual target.
+pub fn tune<E>(img: &DynamicImage, default_quality: u8, tuning: &Tuning, measurable: bool, encode: E) -> Result<(Vec<u8>, Option<Tuned>), OptimizeError>
+where
+    E: Fn(&DynamicImage, u8) -> Result<Vec<u8>, OptimizeError>,
+{
+    let target = tuning.target.filter(|_| measurable);
+    let Some(limit) = tuning.max_bytes else {
+        return match target {
+            Some(target) => search_quality(img, target, tuning.min_quality..=100, |q| encode(img, q)).map(|(d, t)| (d, Some(t))),
+            None => Ok((encode(img, default_quality)?, None)),
+        };
+    };
+
+    let mut scaled: Option<DynamicImage> = None;
+    loop {
+        let current = scaled.as_ref().unwrap_or(img);
+        let scaled_to = scaled.as_ref().map(|s| Dimensions { width: s.width(), height: s.height() });
+        match search_size(limit, tuning.min_quality..=100, |q| encode(current, q))? {
+            Some((data, quality)) => {
+                // Within the byte limit, the perceptual target may allow an even lower quality.
+                let (data, mut tuned) = match target {
+                    Some(target) => search_quality(current, target, tuning.min_quality..=quality, |q| encode(current, q))?,
+                    None => (data, Tuned { quality, ssim: None, target_met: None, fits: None, scaled_to: None }),
+                };
+                tuned.fits = Some(true);
+                tuned.scaled_to = scaled_to;
+                return Ok((data, Some(tuned)));
+            }
+            None => {
+                if let Some(floor) = tuning.min_width && current.width() > floor {
+                    let width = ((current.width() as f64 * SHRINK_STEP) as u32).max(floor);
+                    let height = ((current.height() as u64 * width as u64) / current.width() as u64).max(1) as u32;
+                    scaled = Some(resize(current, width, height, tuning.filter));
+                    continue;
+                }
+                let data = encode(current, tuning.min_quality)?;
+                let tuned = Tuned { quality: tuning.min_quality, ssim: None, target_met: None, fits: Some(false), scaled_to };
+                return Ok((data, Some(tuned)));
+            }
+        }
+    }
+}
+
+/// Binary-searches `range` for the lowest quality whose decoded output meets `target` against
+/// `img`. Falls back to the top of the range, with `target_met: false`, when nothing does.
+pub fn search_quality<E>(img: &DynamicImage, target: QualityTarget, range: RangeInclusive<u8>, encode: E) -> Result<(Vec<u8>, Tuned), OptimizeError>
+where
+    E: Fn(u8) -> Result<Vec<u8>, OptimizeError>,
+{
+    let score = |data: &[u8]| -> Result<f64, OptimizeError> { Ok(ssim(img, &image::load_from_memory(data)?)) };
+    let tuned = |quality, ssim, met| Tuned { quality, ssim: Some(ssim), target_met: Some(met), fits: None, scaled_to: None };
+    let (mut lo, mut hi) = (*range.start(), *range.end());
+    let mut best = None;
+    while lo <= hi {
+        let quality = lo + (hi - lo) / 2;
+        let data = encode(quality)?;
+        let ssim = score(&data)?;
+        if target.is_met(ssim) {
+            best = Some((data, tuned(quality, ssim, true)));
+            if quality == *range.start() {
+                break;
+            }
+            hi = quality - 1;
+        } else {
+            lo = quality + 1;
+        }
+    }
+    match best {
+        Some(found) => Ok(found),
+        None => {
+            let quality = *range.end();
+            let data = encode(quality)?;
+            let ssim = score(&data)?;
+            Ok((data, tuned(quality, ssim, false)))
+        }
+    }
+}
+
+/// Binary-searches `range` for the highest quality whose output is at most `limit` bytes.
+/// `None` when even the lowest one is larger.
+fn search_size<E>(limit: u64, range: RangeInclusive<u8>, encode: E) -> Result<Option<(Vec<u8>, u8)>, OptimizeError>
+where
+    E: Fn(u8) -> Result<Vec<u8>, OptimizeError>,
+{
+    let (mut lo, mut hi) = (*range.start(), *range.end());
+    let mut best = None;
+    while lo <= hi {
+        let quality = lo + (hi - lo) / 2;
+        let data = encode(quality)?;
+        if data.len() as u64 <= limit {
+            best = Some((data, quality));
+            lo = quality + 1;
+        } else {
+            if quality == *range.start() {
+                break;
+            }
+            hi = quality - 1;
+        }
+    }
+    Ok(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_ops::encode_jpg_image;
+    use std::cell::RefCell;
+
+    fn photo() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 251) as u8])
+        }))
+    }
+
+    fn tuning(max_bytes: Option<u64>, min_width: Option<u32>) -> Tuning {
+        Tuning { target: None, max_bytes, min_quality: DEFAULT_MIN_QUALITY, min_width, filter: ResizeFilter::default() }
+    }
+
+    /// Stands in for an encoder whose output grows with width and quality.
+    fn sized(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, OptimizeError> {
+        Ok(vec![0; img.width() as usize * quality as usize])
+    }
+
+    #[test]
+    fn quality_search_finds_the_lowest_passing_quality() {
+        let img = photo();
+        let target = QualityTarget::Ssim(0.95);
+        let (data, tuned) = search_quality(&img, target, 10..=100, |q| encode_jpg_image(&img, q)).unwrap();
+        assert_eq!(tuned.target_met, Some(true));
+        assert!(target.is_met(ssim(&img, &image::load_from_memory(&data).unwrap())));
+        let below = image::load_from_memory(&encode_jpg_image(&img, tuned.quality - 1).unwrap()).unwrap();
+        assert!(!target.is_met(ssim(&img, &below)), "q{} passes too", tuned.quality - 1);
+    }
+
+    #[test]
+    fn quality_search_stops_at_the_floor() {
+        let img = photo();
+        let tried = RefCell::new(Vec::new());
+        let (_, tuned) = search_quality(&img, QualityTarget::Ssim(0.01), 30..=100, |q| {
+            tried.borrow_mut().push(q);
+            encode_jpg_image(&img, q)
+        }).unwrap();
+        assert_eq!(tuned.quality, 30);
+        assert!(tried.borrow().iter().all(|&q| q >= 30), "{:?}", tried.borrow());
+    }
+
+    #[test]
+    fn unreachable_target_falls_back_to_the_top() {
+        let img = photo();
+        let (_, tuned) = search_quality(&img, QualityTarget::Dssim(0.0), 10..=90, |q| encode_jpg_image(&img, q)).unwrap();
+        assert_eq!((tuned.quality, tuned.target_met), (90, Some(false)));
+    }
+
+    #[test]
+    fn size_search_finds_the_highest_fitting_quality() {
+        // (limit, range, expected)
+        let cases = [(555, 10..=100, Some(55)), (10_000, 10..=100, Some(100)), (100, 10..=100, Some(10)), (99, 10..=100, None), (0, 1..=100, None)];
+        for (limit, range, expected) in cases {
+            let found = search_size(limit, range, |q| Ok(vec![0; q as usize * 10])).unwrap();
+            assert_eq!(found.map(|(data, q)| { assert!(data.len() as u64 <= limit); q }), expected, "limit {}", limit);
+        }
+    }
+
+    #[test]
+    fn unreachable_budget_is_reported() {
+        let img = DynamicImage::new_rgb8(100, 50);
+        let (data, tuned) = tune(&img, 80, &tuning(Some(500), None), true, sized).unwrap();
+        assert_eq!(data.len(), 100 * DEFAULT_MIN_QUALITY as usize);
+        let tuned = tuned.unwrap();
+        assert_eq!((tuned.quality, tuned.fits, tuned.scaled_to), (DEFAULT_MIN_QUALITY, Some(false), None));
+    }
+
+    #[test]
+    fn images_shrink_to_fit_down_to_min_width() {
+        let img = DynamicImage::new_rgb8(100, 50);
+        // 100 and 85 wide are too large even at the minimum quality; 72 wide fits at quality 11.
+        let (data, tuned) = tune(&img, 80, &tuning(Some(800), Some(50)), true, sized).unwrap();
+        let tuned = tuned.unwrap();
+        assert_eq!((tuned.quality, tuned.fits), (11, Some(true)));
+        assert_eq!(tuned.scaled_to, Some(Dimensions { width: 72, height: 35 }));
+        assert_eq!(data.len(), 72 * 11);
+
+        // The floor stops the shrinking; the smallest attempt is kept and marked as not fitting.
+        let (_, tuned) = tune(&img, 80, &tuning(Some(800), Some(90)), true, sized).unwrap();
+        let tuned = tuned.unwrap();
+        assert_eq!((tuned.quality, tuned.fits), (DEFAULT_MIN_QUALITY, Some(false)));
+        assert_eq!(tuned.scaled_to.map(|d| d.width), Some(90));
+    }
+}
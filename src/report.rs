@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::formats::ImageKind;
+use crate::fs_utils::write_atomic;
+use crate::optimizer::{Event, Plan};
+use crate::results::{Dimensions, NameCollision, OutputKind, RunSummary, serialize_secs};
+
+pub const REPORT_VERSION: u32 = 1;
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Everything a run produced, in the shape written by `--report`. Durations are in seconds.
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub version: u32,
+    pub dry_run: bool,
+    #[serde(serialize_with = "serialize_secs")]
+    pub wall_time: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub copy_duration: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub scan_duration: Duration,
+    /// Files skipped because the incremental cache had them.
+    pub cached: &'a [PathBuf],
+    /// Outputs several files would have written, and what each wrote instead.
+    pub collisions: &'a [NameCollision],
+    /// WebP files left out as the WebP version of another image.
+    pub earlier_outputs: &'a [PathBuf],
+    #[serde(flatten)]
+    pub summary: &'a RunSummary,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(plan: &'a Plan, summary: &'a RunSummary, dry_run: bool, wall_time: Duration) -> Self {
+        Self {
+            version: REPORT_VERSION,
+            dry_run,
+            wall_time,
+            copy_duration: plan.copy_duration,
+            scan_duration: plan.scan_duration,
+            cached: &plan.cached,
+            collisions: &plan.collisions,
+            earlier_outputs: &plan.earlier_outputs,
+            summary,
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        write_atomic(path, json.as_bytes())
+    }
+}
+
+/// One NDJSON line describing `event`, or `None` for purely cosmetic events.
+pub fn event_line(event: &Event) -> Option<String> {
+    let value = match event {
+        Event::FileStarted { path } => json!({ "event": "file_started", "path": path }),
+        Event::FileFinished { result } => json!({ "event": "file_finished", "result": result }),
+        Event::NotFound { path } => json!({ "event": "not_found", "path": path }),
+        Event::Unsupported { path } => json!({ "event": "unsupported", "path": path }),
+        Event::CopyFailed { path, error } => json!({ "event": "copy_failed", "path": path, "error": error.to_string() }),
+        Event::NameCollision { collision } => json!({ "event": "name_collision", "collision": collision }),
+        Event::EarlierOutput { path } => json!({ "event": "earlier_output", "path": path }),
+        Event::CacheSaveFailed { path, error } => json!({ "event": "cache_save_failed", "path": path, "error": error.to_string() }),
+        Event::Watching { dir } => json!({ "event": "watching", "dir": dir }),
+        Event::WatchError { error } => json!({ "event": "watch_error", "error": error.to_string() }),
+        _ => return None,
+    };
+    Some(value.to_string())
+}
+
+/// Maps each source image to every file generated from it, with intrinsic sizes, for building
+/// `srcset` attributes. Written by `--manifest`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub images: BTreeMap<String, ManifestImage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestImage {
+    /// The input file, for images whose key is a copy or an `out_dir` path.
+    #[serde(default)]
+    pub source: String,
+    pub width: u32,
+    pub height: u32,
+    /// Full-size outputs and responsive variants, smallest first.
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub path: String,
+    /// MIME type, e.g. `image/webp`.
+    #[serde(rename = "type")]
+    pub mime: String,
+    pub width: u32,
+    pub height: u32,
+    pub size: u64,
+}
+
+impl Manifest {
+    /// Loads an existing manifest so images skipped this run keep their entries. Starts empty if
+    /// the file is missing or unreadable.
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str::<Manifest>(&text).ok())
+            .filter(|m| m.version == MANIFEST_VERSION)
+            .unwrap_or(Manifest { version: MANIFEST_VERSION, images: BTreeMap::new() })
+    }
+
+    /// Adds or replaces the entries of every successfully processed file of `summary`.
+    pub fn update(&mut self, summary: &RunSummary) {
+        for file in summary.files.iter().filter(|f| !f.is_failed()) {
+            let dimensions = file.dimensions.or_else(|| {
+                image::image_dimensions(&file.path).ok().map(|(width, height)| Dimensions { width, height })
+            });
+            let Some(full) = dimensions else { continue };
+            let mut files: Vec<ManifestFile> = file.outputs.iter()
+                .map(|o| {
+                    let size = o.tuned.and_then(|t| t.scaled_to).or(o.variant).unwrap_or(full);
+                    ManifestFile {
+                        path: o.path.to_string_lossy().into_owned(),
+                        mime: mime_type(o.kind, file.format).to_string(),
+                        width: size.width,
+                        height: size.height,
+                        size: o.new_size,
+                    }
+                })
+                .collect();
+            files.sort_by_key(|f| (f.width, f.mime.clone()));
+            let source = file.source.to_string_lossy().into_owned();
+            let image = ManifestImage { source, width: full.width, height: full.height, files };
+            self.images.insert(file.path.to_string_lossy().into_owned(), image);
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        write_atomic(path, json.as_bytes())
+    }
+}
+
+fn mime_type(output: OutputKind, format: ImageKind) -> &'static str {
+    match (output, format) {
+        (OutputKind::Original, format) => format.mime_type(),
+        (OutputKind::WebP, _) => "image/webp",
+        (OutputKind::Avif, _) => "image/avif",
+    }
+}
@@ -0,0 +1,207 @@
+use serde::{Serialize, Serializer};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::error::OptimizeError;
+use crate::formats::ImageKind;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputKind {
+    /// The optimized original, written in place or converted (see `FileResult::format`).
+    Original,
+    WebP,
+    Avif,
+}
+
+/// A file several sources would have written, e.g. `logo.webp` from `logo.png` and `logo.jpg`.
+#[derive(Debug, Clone, Serialize)]
+pub struct NameCollision {
+    pub path: PathBuf,
+    pub sources: Vec<PathBuf>,
+    /// What each of `sources` writes instead. Unchanged for an original that keeps its name.
+    pub outputs: Vec<PathBuf>,
+}
+
+/// Outcome of producing one output file from a source image.
+#[derive(Debug, Serialize)]
+pub struct OutputResult {
+    pub kind: OutputKind,
+    pub path: PathBuf,
+    pub original_size: u64,
+    /// Size of the output on disk. Equal to `original_size` when the original was already optimal.
+    pub new_size: u64,
+    /// Pixel size of a responsive (srcset) variant. `None` for the full-size outputs.
+    pub variant: Option<Dimensions>,
+    /// Set when the quality was searched instead of fixed.
+    pub tuned: Option<Tuned>,
+    #[serde(serialize_with = "serialize_secs")]
+    pub duration: Duration,
+    #[serde(serialize_with = "serialize_error")]
+    pub error: Option<OptimizeError>,
+}
+
+impl OutputResult {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn saved(&self) -> u64 {
+        if self.is_ok() { self.original_size.saturating_sub(self.new_size) } else { 0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Quality picked by a `--target-ssim`/`--target-dssim` or `--max-bytes` search.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Tuned {
+    pub quality: u8,
+    /// Measured SSIM against the source, with a perceptual target.
+    pub ssim: Option<f64>,
+    /// Whether the perceptual target was reached. `None` without one.
+    pub target_met: Option<bool>,
+    /// Whether the output fits `max_bytes`. `None` without a limit.
+    pub fits: Option<bool>,
+    /// Set when the image was downscaled to fit `max_bytes`.
+    pub scaled_to: Option<Dimensions>,
+}
+
+/// A downscale applied because of `max_width`/`max_height`/`max_pixels`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Resized {
+    pub from: Dimensions,
+    pub to: Dimensions,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileResult {
+    /// Where the optimized original went.
+    pub path: PathBuf,
+    /// The input file, before any safe-mode copy. Differs from `path` except for images
+    /// optimized in place under their own name.
+    pub source: PathBuf,
+    pub kind: ImageKind,
+    /// Format of the optimized original. Differs from `kind` when the image was converted.
+    pub format: ImageKind,
+    /// The file's extension didn't match its content.
+    pub extension_mismatch: bool,
+    pub original_size: u64,
+    /// Pixel size of the full-size outputs. Only known when the image had to be decoded.
+    pub dimensions: Option<Dimensions>,
+    pub resized: Option<Resized>,
+    pub outputs: Vec<OutputResult>,
+}
+
+impl FileResult {
+    /// The full-size output of `kind`.
+    pub fn output(&self, kind: OutputKind) -> Option<&OutputResult> {
+        self.outputs.iter().find(|o| o.kind == kind && o.variant.is_none())
+    }
+
+    pub fn variants(&self) -> impl Iterator<Item = &OutputResult> {
+        self.outputs.iter().filter(|o| o.variant.is_some())
+    }
+
+    pub fn saved(&self, kind: OutputKind) -> u64 {
+        self.output(kind).map(|o| o.saved()).unwrap_or(0)
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.outputs.iter().any(|o| !o.is_ok())
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &OutputResult> {
+        self.outputs.iter().filter(|o| !o.is_ok())
+    }
+}
+
+/// Durations serialize as seconds.
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    pub files: Vec<FileResult>,
+    pub total_input_size: u64,
+    pub saved_orig: u64,
+    pub saved_webp: u64,
+    pub saved_avif: u64,
+    #[serde(serialize_with = "serialize_secs")]
+    pub time_jpg: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub time_png: Duration,
+    /// Originals in any other format (WebP, GIF, TIFF, …).
+    #[serde(serialize_with = "serialize_secs")]
+    pub time_other: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub time_webp: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub time_avif: Duration,
+    /// Responsive variants written, across all formats.
+    pub variants: usize,
+    #[serde(serialize_with = "serialize_secs")]
+    pub time_variants: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub process_duration: Duration,
+}
+
+impl RunSummary {
+    pub(crate) fn from_results(files: Vec<FileResult>, process_duration: Duration) -> Self {
+        let mut summary = RunSummary { process_duration, ..Default::default() };
+        for f in &files {
+            summary.total_input_size += f.original_size;
+            for o in f.variants() {
+                summary.variants += o.is_ok() as usize;
+                summary.time_variants += o.duration;
+            }
+            // Variants are extra files, not replacements; they'd skew the savings.
+            for o in f.outputs.iter().filter(|o| o.variant.is_none()) {
+                match o.kind {
+                    OutputKind::Original => {
+                        summary.saved_orig += o.saved();
+                        match f.format {
+                            ImageKind::Jpeg => summary.time_jpg += o.duration,
+                            ImageKind::Png => summary.time_png += o.duration,
+                            _ => summary.time_other += o.duration,
+                        }
+                    }
+                    OutputKind::WebP => {
+                        summary.saved_webp += o.saved();
+                        summary.time_webp += o.duration;
+                    }
+                    OutputKind::Avif => {
+                        summary.saved_avif += o.saved();
+                        summary.time_avif += o.duration;
+                    }
+                }
+            }
+        }
+        summary.files = files;
+        summary
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &FileResult> {
+        self.files.iter().filter(|f| f.is_failed())
+    }
+
+    pub fn has_output(&self, kind: OutputKind) -> bool {
+        self.files.iter().any(|f| f.output(kind).is_some())
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.failed().next().is_some()
+    }
+}
+
+pub(crate) fn serialize_secs<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(duration.as_secs_f64())
+}
+
+fn serialize_error<S: Serializer>(error: &Option<OptimizeError>, s: S) -> Result<S::Ok, S::Error> {
+    match error {
+        Some(e) => s.serialize_some(&e.to_string()),
+        None => s.serialize_none(),
+    }
+}
@@ -0,0 +1,223 @@
+use notify::{EventKind, RecursiveMode, Watcher};
+use notify::event::{AccessKind, AccessMode};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, mpsc};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::backup::is_backup_path;
+use crate::cache::hash_file;
+use crate::config::ConfigResolver;
+use crate::filters::ScanFilter;
+use crate::formats::ImageKind;
+use crate::fs_utils::is_temp_file;
+use crate::optimizer::{Event, Optimizer, Plan, PlanEntry, drop_generated_siblings, resolve_collisions, safe_dir_name};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// A file counts as fully written once its size and mtime stayed the same for this long.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+type Fingerprint = (u64, Option<SystemTime>);
+
+/// Images in the directories of `paths` that share a stem with one of them, other than `paths`.
+fn same_stem_images(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let stem = |p: &Path| p.file_stem().unwrap_or_default().to_string_lossy().to_lowercase();
+    let mut found = Vec::new();
+    for path in paths {
+        let Some(Ok(entries)) = path.parent().map(fs::read_dir) else { continue };
+        for entry in entries.flatten() {
+            let other = entry.path();
+            if stem(&other) == stem(path) && !paths.contains(&other) && !found.contains(&other) && other.is_file() {
+                found.push(other);
+            }
+        }
+    }
+    found
+}
+
+fn fingerprint(path: &Path) -> Option<Fingerprint> {
+    let meta = fs::metadata(path).ok()?;
+    meta.is_file().then(|| (meta.len(), meta.modified().ok()))
+}
+
+struct Pending {
+    seen: Fingerprint,
+    changed_at: Instant,
+}
+
+impl Optimizer {
+    /// Watches `dir` and optimizes supported images as soon as they have finished being written.
+    ///
+    /// In replace mode files are optimized in place; with `out_dir` they are written straight into
+    /// it; otherwise they are copied into the mirrored `__optimized` directory first. Blocks until
+    /// `stop` is set.
+    pub fn watch<F>(&self, dir: &Path, stop: &AtomicBool, on_event: F) -> io::Result<()>
+    where
+        F: Fn(&Event) + Sync,
+    {
+        let options = self.options();
+        let filter = ScanFilter::new(options)?;
+        // Event paths are absolute; the mirror needs a matching root to strip.
+        let dir = &fs::canonicalize(dir)?;
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).map_err(io::Error::other)?;
+        watcher.watch(dir, RecursiveMode::Recursive).map_err(io::Error::other)?;
+        on_event(&Event::Watching { dir });
+        // An output directory inside the watched one only ever holds our own writes.
+        let out_dir = match &options.out_dir {
+            Some(out_dir) if !options.replace => {
+                fs::create_dir_all(out_dir)?;
+                Some(fs::canonicalize(out_dir)?)
+            }
+            _ => None,
+        };
+
+        let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+        // What this process last wrote to each output, so our own writes don't re-trigger.
+        let written: Mutex<HashMap<PathBuf, Fingerprint>> = Mutex::new(HashMap::new());
+
+        while !stop.load(Ordering::Relaxed) {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(Ok(event)) => {
+                    let relevant = matches!(
+                        event.kind,
+                        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Access(AccessKind::Close(AccessMode::Write))
+                    );
+                    if relevant {
+                        for path in event.paths {
+                            if is_temp_file(path.file_name().unwrap_or_default())
+                                || is_backup_path(&path)
+                                || out_dir.as_ref().is_some_and(|out| path.starts_with(out))
+                                || ImageKind::detect(&path).is_none() {
+                                continue;
+                            }
+                            let Some(fp) = fingerprint(&path) else { continue };
+                            if written.lock().unwrap().get(&path) == Some(&fp) {
+                                continue;
+                            }
+                            match pending.get_mut(&path) {
+                                Some(p) if p.seen == fp => {}
+                                Some(p) => { p.seen = fp; p.changed_at = Instant::now(); }
+                                None => { pending.insert(path, Pending { seen: fp, changed_at: Instant::now() }); }
+                            }
+                        }
+                    }
+                }
+                Ok(Err(e)) => on_event(&Event::WatchError { error: &io::Error::other(e) }),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+
+            let mut ready = Vec::new();
+            pending.retain(|path, p| match fingerprint(path) {
+                None => false,
+                Some(fp) if fp != p.seen => {
+                    p.seen = fp;
+                    p.changed_at = Instant::now();
+                    true
+                }
+                Some(_) if p.changed_at.elapsed() >= SETTLE_TIME => {
+                    ready.push(path.clone());
+                    false
+                }
+                Some(_) => true,
+            });
+            if ready.is_empty() {
+                continue;
+            }
+
+            // Config files may have changed since the last batch.
+            let configs = ConfigResolver::new(options.settings.clone(), options.overrides.clone(), options.config_files, options.ignore_files);
+            let mut plan = Plan::default();
+            for path in &ready {
+                match self.watch_entry(dir, path, &configs, &filter, true) {
+                    Ok(Some(entry)) => plan.files.push(entry),
+                    Ok(None) => {}
+                    Err(error) => on_event(&Event::CopyFailed { path, error: &error }),
+                }
+            }
+
+            // Names are checked against the unchanged same-stem images too, as a full scan would.
+            let mut neighbors = HashSet::new();
+            for path in same_stem_images(&ready) {
+                if let Ok(Some(entry)) = self.watch_entry(dir, &path, &configs, &filter, false) {
+                    neighbors.insert(entry.source.clone());
+                    plan.files.push(entry);
+                }
+            }
+            for path in drop_generated_siblings(&mut plan.files, self.options().replace || self.options().out_dir.is_none()).iter().filter(|p| !neighbors.contains(*p)) {
+                on_event(&Event::EarlierOutput { path });
+            }
+            match resolve_collisions(&mut plan.files) {
+                Ok(collisions) => collisions.iter().for_each(|collision| on_event(&Event::NameCollision { collision })),
+                Err(error) => {
+                    on_event(&Event::WatchError { error: &error });
+                    continue;
+                }
+            }
+            plan.files.retain(|entry| !neighbors.contains(&entry.source));
+
+            self.process(&plan, |event| {
+                if let Event::FileFinished { result } = event {
+                    let mut written = written.lock().unwrap();
+                    for output in &result.outputs {
+                        if let Some(fp) = fingerprint(&output.path) {
+                            written.insert(output.path.clone(), fp);
+                        }
+                    }
+                }
+                on_event(event);
+            });
+        }
+        Ok(())
+    }
+
+    /// Plans `path` like a scan would. With `copy`, a safe-mode copy is put in place to work on.
+    fn watch_entry(&self, root: &Path, path: &Path, configs: &ConfigResolver, filter: &ScanFilter, copy: bool) -> io::Result<Option<PlanEntry>> {
+        let Some(kind) = ImageKind::detect(path) else { return Ok(None) };
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        if filter.skips_file(rel) || filter.is_too_deep(rel) || (!filter.follow_links && path.is_symlink()) {
+            return Ok(None);
+        }
+        // A scan would not have entered an excluded or ignored directory on the way.
+        for dir in path.ancestors().skip(1).take_while(|d| d.starts_with(root) && *d != root) {
+            let parent = configs.resolve_file(dir)?;
+            let name = dir.file_name().unwrap_or_default();
+            if parent.is_excluded(name) || parent.is_ignored(name, true) {
+                return Ok(None);
+            }
+        }
+        let dir_config = configs.resolve_file(path)?;
+        let name = path.file_name().unwrap_or_default();
+        if dir_config.is_excluded(name) || dir_config.is_ignored(name, false) || dir_config.settings.is_variant_name(name) {
+            return Ok(None);
+        }
+        let settings = dir_config.settings.clone();
+        let mut entry = if self.options().replace {
+            PlanEntry::new(kind, path.to_path_buf(), path.to_path_buf(), settings)
+        } else if let Some(out_dir) = &self.options().out_dir {
+            let target = out_dir.join(rel);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            PlanEntry::new(kind, target.clone(), target, settings).with_source(path.to_path_buf())
+        } else {
+            let target_root = safe_dir_name(root, &configs.resolve(root)?.settings.output_suffix);
+            let target = target_root.join(rel);
+            if copy {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::copy(path, &target)?;
+            }
+            PlanEntry::new(kind, target.clone(), target, settings).with_input(path.to_path_buf())
+        };
+        if self.options().incremental && copy {
+            entry.hash = hash_file(&entry.source).ok();
+        }
+        Ok(Some(entry))
+    }
+}